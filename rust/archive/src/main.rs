@@ -0,0 +1,159 @@
+/**
+* Module     : main.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Archive canister, spawned by the token canister to hold a contiguous range
+// of its transaction history once the local log grows too large.
+use candid::candid_method;
+use ic_cdk_macros::*;
+use ic_kit::{ic, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableCell, StableLog};
+use std::cell::RefCell;
+use token::OpRecord;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const LEDGER: MemoryId = MemoryId::new(0);
+const RECORDS_INDEX: MemoryId = MemoryId::new(1);
+const RECORDS_DATA: MemoryId = MemoryId::new(2);
+
+thread_local! {
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+        MemoryManager::init(DefaultMemoryImpl::default());
+
+    // principal of the token canister allowed to append records
+    static LEDGER_ID: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(memory(LEDGER), Vec::new()).expect("failed to init ledger cell"),
+    );
+
+    static RECORDS: StableLog<OpRecord, Memory, Memory> =
+        StableLog::init(memory(RECORDS_INDEX), memory(RECORDS_DATA))
+            .expect("failed to init record log");
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(id))
+}
+
+fn ledger() -> Principal {
+    LEDGER_ID.with(|c| Principal::from_slice(c.borrow().get()))
+}
+
+#[init]
+#[candid_method(init)]
+fn init(ledger: Principal) {
+    LEDGER_ID.with(|c| {
+        c.borrow_mut()
+            .set(ledger.as_slice().to_vec())
+            .expect("failed to write ledger id")
+    });
+}
+
+#[update(name = "append")]
+#[candid_method(update)]
+fn append(records: Vec<OpRecord>) {
+    assert_eq!(ic::caller(), ledger());
+    let mut next = match history_size() {
+        0 => records.first().map_or(0, |r| r.index),
+        size => first_index() + size,
+    };
+    RECORDS.with(|l| {
+        for record in records.iter() {
+            assert_eq!(record.index, next, "records must be appended in order");
+            l.append(record).expect("failed to append record");
+            next += 1;
+        }
+    });
+}
+
+#[query(name = "firstIndex")]
+#[candid_method(query, rename = "firstIndex")]
+fn first_index() -> usize {
+    RECORDS.with(|l| l.get(0).map_or(0, |r| r.index))
+}
+
+#[query(name = "historySize")]
+#[candid_method(query, rename = "historySize")]
+fn history_size() -> usize {
+    RECORDS.with(|l| l.len() as usize)
+}
+
+#[query(name = "getTransaction")]
+#[candid_method(query, rename = "getTransaction")]
+fn get_transaction(index: usize) -> OpRecord {
+    let offset = index
+        .checked_sub(first_index())
+        .expect("transaction index out of range");
+    RECORDS
+        .with(|l| l.get(offset as u64))
+        .expect("transaction index out of range")
+}
+
+#[query(name = "getTransactions")]
+#[candid_method(query, rename = "getTransactions")]
+fn get_transactions(start: usize, limit: usize) -> Vec<OpRecord> {
+    let first = first_index();
+    let from = std::cmp::max(start, first) - first;
+    let to = std::cmp::min(start.saturating_add(limit).saturating_sub(first), history_size());
+    RECORDS.with(|l| (from..to).filter_map(|i| l.get(i as u64)).collect())
+}
+
+#[cfg(any(target_arch = "wasm32", test))]
+fn main() {}
+
+#[cfg(not(any(target_arch = "wasm32", test)))]
+fn main() {
+    candid::export_service!();
+    std::print!("{}", __export_service());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::types::number::Nat;
+    use ic_kit::{mock_principals::{alice, bob}, MockContext};
+    use assert_panic::assert_panic;
+    use token::{Operation, TransactionStatus};
+
+    fn record(index: usize) -> OpRecord {
+      OpRecord {
+        caller: None,
+        op: Operation::Transfer,
+        index,
+        from: alice().into(),
+        to: bob().into(),
+        amount: Nat::from(10),
+        fee: Nat::from(1),
+        timestamp: 1,
+        status: TransactionStatus::Succeeded,
+        memo: None,
+        created_at_time: None,
+      }
+    }
+
+    #[test]
+    fn append_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      init(alice());
+
+      append(vec![record(5), record(6)]);
+      append(vec![record(7)]);
+      assert_eq!(first_index(), 5, "first_index did not return the index of the first record");
+      assert_eq!(history_size(), 3, "history_size did not return the number of records");
+      assert_eq!(get_transaction(6).index, 6, "get_transaction did not map the index to the right record");
+      assert_eq!(get_transactions(0, 7).len(), 2, "get_transactions did not clip the range to the held records");
+      assert_eq!(get_transactions(6, 10).len(), 2, "get_transactions did not clip the range to the held records");
+      assert_panic!({ get_transaction(4); });
+      assert_panic!(append(vec![record(9)]));
+
+      ctx.update_caller(bob());
+      assert_panic!(append(vec![record(8)]));
+    }
+}
@@ -1,8 +1,14 @@
 sudo dfx canister --no-wallet create --all
-cargo run > token.did
+cargo run -p token > token.did
+cargo run -p archive > archive/archive.did
 ic-cdk-optimizer target/wasm32-unknown-unknown/release/token.wasm -o target/wasm32-unknown-unknown/release/opt.wasm
 sudo dfx build token
 OWNER="principal \"$( \
    dfx identity get-principal
 )\""
 sudo dfx canister --no-wallet install token --argument "(\"test logo\", \"test token\", \"TT\", 8:nat8, 100000000:nat64, $OWNER, 0)" -m=reinstall
+# archive canisters are spawned by the token canister from the uploaded wasm
+cargo build --target wasm32-unknown-unknown --release -p archive
+ic-cdk-optimizer target/wasm32-unknown-unknown/release/archive.wasm -o target/wasm32-unknown-unknown/release/archive-opt.wasm
+ARCHIVE_WASM="$(od -An -v -tx1 target/wasm32-unknown-unknown/release/archive-opt.wasm | tr -d ' \n' | sed 's/../\\&/g')"
+sudo dfx canister --no-wallet call token setArchiveWasm "(blob \"$ARCHIVE_WASM\")"
@@ -0,0 +1,279 @@
+/**
+* Module     : archive.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+use crate::{certification, storage, Metadata};
+use candid::{CandidType, Deserialize};
+use ic_kit::interfaces::management::{
+    CanisterSettings, CreateCanister, CreateCanisterArgument, InstallCode, InstallCodeArgument,
+    InstallMode,
+};
+use ic_kit::interfaces::Method;
+use ic_kit::{ic, Principal};
+use std::future::Future;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct ArchiveOptions {
+    // archiving starts once this many records are held locally
+    pub trigger_threshold: usize,
+    // how many of the oldest local records are moved per round
+    pub num_records_to_archive: usize,
+    // a new archive canister is spawned once the last one holds this many records
+    pub max_records_per_archive: usize,
+    pub cycles_for_archive_creation: u64,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        ArchiveOptions {
+            trigger_threshold: 2_000,
+            num_records_to_archive: 1_000,
+            max_records_per_archive: 10_000_000,
+            cycles_for_archive_creation: 2_000_000_000_000,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: usize,
+    pub length: usize,
+}
+
+// A round left behind by a callback that trapped is given up after this long,
+// its calls are answered well before.
+const ARCHIVING_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(Default)]
+pub struct Archives {
+    pub options: ArchiveOptions,
+    pub archives: Vec<ArchiveInfo>,
+    // created for the next archive but not installed yet, the next round
+    // installs it instead of creating another canister
+    pub uninstalled: Option<Principal>,
+    // start of the round in progress
+    archiving_since: Option<u64>,
+    // archives that may run older code than the archive wasm, they decode
+    // the records they are sent and are upgraded before the next append
+    pub outdated: Vec<Principal>,
+}
+
+impl Archives {
+    fn is_archiving(&self, now: u64) -> bool {
+        matches!(self.archiving_since, Some(since) if now < since.saturating_add(ARCHIVING_TIMEOUT))
+    }
+}
+
+// Ends the round however `archive_records` returns.
+struct ArchivingGuard;
+
+impl Drop for ArchivingGuard {
+    fn drop(&mut self) {
+        ic::get_mut::<Archives>().archiving_since = None;
+    }
+}
+
+/// Index of the oldest record still held by this canister, every record
+/// before it lives in one of the archives.
+pub fn first_local_index() -> usize {
+    match ic::get::<Archives>().archives.last() {
+        Some(last) => last.start + last.length,
+        None => 0,
+    }
+}
+
+pub fn archive_of(index: usize) -> Option<ArchiveInfo> {
+    ic::get::<Archives>()
+        .archives
+        .iter()
+        .find(|a| a.start <= index && index < a.start + a.length)
+        .cloned()
+}
+
+/// The parts of `[start, start + limit)` that have to be fetched from archives.
+pub fn archived_ranges(start: usize, limit: usize) -> Vec<ArchiveInfo> {
+    let end = start.saturating_add(limit);
+    let mut ranges = Vec::new();
+    for a in ic::get::<Archives>().archives.iter() {
+        let from = std::cmp::max(start, a.start);
+        let to = std::cmp::min(end, a.start + a.length);
+        if from < to {
+            ranges.push(ArchiveInfo {
+                canister_id: a.canister_id,
+                start: from,
+                length: to - from,
+            });
+        }
+    }
+    ranges
+}
+
+/// Has every archive upgraded to the archive wasm at the start of the next
+/// round. Called when the archive wasm or this canister is upgraded, archives
+/// spawned before may not decode the records it now appends. The upgrade
+/// cannot happen right away, `post_upgrade` is not allowed to make calls.
+pub fn mark_outdated() {
+    let state = ic::get_mut::<Archives>();
+    state.outdated = state.archives.iter().map(|a| a.canister_id).collect();
+    state.outdated.dedup();
+}
+
+/// Moves the oldest records to an archive canister in the background once the
+/// local log has passed the trigger threshold. Nothing happens until an
+/// archive wasm has been uploaded.
+pub fn maybe_archive() {
+    let state = ic::get::<Archives>();
+    if state.is_archiving(ic::time())
+        || storage::local_op_count() < state.options.trigger_threshold
+        || !storage::has_archive_wasm()
+    {
+        return;
+    }
+    spawn(archive_records());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn spawn<F: 'static + Future<Output = ()>>(future: F) {
+    ic_cdk::block_on(future);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn<F: 'static + Future<Output = ()>>(future: F) {
+    async_std::task::block_on(future);
+}
+
+pub async fn archive_records() {
+    let now = ic::time();
+    let state = ic::get_mut::<Archives>();
+    if state.is_archiving(now) {
+        return;
+    }
+    state.archiving_since = Some(now);
+    let _guard = ArchivingGuard;
+    if let Err(e) = archive_oldest_records().await {
+        ic::print(format!("failed to archive records: {}", e));
+    }
+}
+
+async fn archive_oldest_records() -> Result<(), String> {
+    upgrade_outdated_archives().await?;
+    let options = ic::get::<Archives>().options.clone();
+    let last = ic::get::<Archives>().archives.last().cloned();
+    let (canister_id, room) = match last {
+        Some(last) if last.length < options.max_records_per_archive => (
+            last.canister_id,
+            options.max_records_per_archive - last.length,
+        ),
+        _ => (
+            create_archive(&options).await?,
+            options.max_records_per_archive,
+        ),
+    };
+    let start = first_local_index();
+    let records = storage::ops(
+        start,
+        std::cmp::min(options.num_records_to_archive, room),
+    );
+    if records.is_empty() {
+        return Ok(());
+    }
+    let length = records.len();
+    ic::call::<_, (), _>(canister_id, "append", (records,))
+        .await
+        .map_err(|(code, msg)| format!("append to {} rejected ({:?}): {}", canister_id, code, msg))?;
+    for index in start..start + length {
+        storage::remove_op(index);
+        certification::remove_record(index);
+    }
+    // Records are only ever appended to the newest archive.
+    let last = ic::get_mut::<Archives>().archives.last_mut().unwrap();
+    last.length += length;
+    Ok(())
+}
+
+async fn create_archive(options: &ArchiveOptions) -> Result<Principal, String> {
+    let canister_id = match ic::get::<Archives>().uninstalled {
+        Some(canister_id) => canister_id,
+        None => {
+            let canister_id = create_canister(options).await?;
+            ic::get_mut::<Archives>().uninstalled = Some(canister_id);
+            canister_id
+        }
+    };
+    install_code(
+        canister_id,
+        InstallMode::Install,
+        candid::encode_args((ic::id(),)).unwrap(),
+    )
+    .await?;
+    let start = first_local_index();
+    let state = ic::get_mut::<Archives>();
+    state.uninstalled = None;
+    state.archives.push(ArchiveInfo {
+        canister_id,
+        start,
+        length: 0,
+    });
+    Ok(canister_id)
+}
+
+// Archives keep their records and ledger id in stable memory, an upgrade
+// only replaces their code.
+async fn upgrade_outdated_archives() -> Result<(), String> {
+    while let Some(canister_id) = ic::get::<Archives>().outdated.last().copied() {
+        install_code(
+            canister_id,
+            InstallMode::Upgrade,
+            candid::encode_args(()).unwrap(),
+        )
+        .await?;
+        ic::get_mut::<Archives>().outdated.pop();
+    }
+    Ok(())
+}
+
+async fn install_code(
+    canister_id: Principal,
+    mode: InstallMode,
+    arg: Vec<u8>,
+) -> Result<(), String> {
+    InstallCode::perform(
+        Principal::management_canister(),
+        (InstallCodeArgument {
+            mode,
+            canister_id,
+            wasm_module: storage::archive_wasm(),
+            arg,
+        },),
+    )
+    .await
+    .map_err(|(code, msg)| {
+        format!(
+            "install_code on {} rejected ({:?}): {}",
+            canister_id, code, msg
+        )
+    })
+}
+
+async fn create_canister(options: &ArchiveOptions) -> Result<Principal, String> {
+    let settings = CanisterSettings {
+        controllers: Some(vec![ic::id(), ic::get::<Metadata>().owner]),
+        compute_allocation: None,
+        memory_allocation: None,
+        freezing_threshold: None,
+    };
+    let (created,) = CreateCanister::perform_with_payment(
+        Principal::management_canister(),
+        (CreateCanisterArgument {
+            settings: Some(settings),
+        },),
+        options.cycles_for_archive_creation,
+    )
+    .await
+    .map_err(|(code, msg)| format!("create_canister rejected ({:?}): {}", code, msg))?;
+    Ok(created.canister_id)
+}
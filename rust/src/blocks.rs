@@ -0,0 +1,73 @@
+/**
+* Module     : blocks.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Every record is encoded as a block that carries the hash of the block
+// before it, so the history forms a chain anyone can re-derive. Block hashes
+// stay in stable memory after their records are archived, the chain of a
+// canister upgraded from before blocks starts at its first local record.
+use crate::storage;
+use token::{block_hash, verify_chain, Block, OpRecord};
+
+fn parent_hash(index: usize) -> Option<Vec<u8>> {
+    index
+        .checked_sub(1)
+        .and_then(storage::block_hash)
+        .map(|hash| hash.to_vec())
+}
+
+pub fn encode(record: OpRecord) -> Vec<u8> {
+    Block {
+        parent_hash: parent_hash(record.index),
+        transaction: record,
+    }
+    .encode()
+}
+
+pub fn append(record: &OpRecord) {
+    let index = record.index;
+    storage::set_block_hash(index, block_hash(&encode(record.clone())));
+}
+
+pub fn tip_hash(history_size: usize) -> Option<Vec<u8>> {
+    parent_hash(history_size)
+}
+
+// Most blocks re-derived by a single `verify` call.
+pub const MAX_VERIFY_LENGTH: usize = 2_000;
+
+/// Chains the local records added before blocks existed.
+pub fn index_local_records() {
+    for record in storage::ops(0, usize::MAX) {
+        if storage::block_hash(record.index).is_none() {
+            append(&record);
+        }
+    }
+}
+
+/// Re-derives the chain over the local records `[start, start + length)`
+/// from the stored hash of the block before `start`, and checks that it ends
+/// at the stored hash of its last block. Returns the number of blocks checked,
+/// at most `MAX_VERIFY_LENGTH`.
+pub fn verify(start: usize, length: usize, first_index: usize) -> Result<usize, String> {
+    if start < first_index {
+        return Err(format!(
+            "block {} is archived, local blocks start at {}",
+            start, first_index
+        ));
+    }
+    let records = storage::ops(start, length.min(MAX_VERIFY_LENGTH));
+    let last = match records.last() {
+        Some(record) => record.index,
+        None => return Ok(0),
+    };
+    let blocks: Vec<Vec<u8>> = records.into_iter().map(encode).collect();
+    let tip = verify_chain(parent_hash(start), &blocks)?;
+    if tip != storage::block_hash(last).map(|hash| hash.to_vec()) {
+        return Err(format!("block {} does not match its stored hash", last));
+    }
+    Ok(blocks.len())
+}
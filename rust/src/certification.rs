@@ -0,0 +1,276 @@
+/**
+* Module     : certification.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Merkle tree over the balances and the records held locally, its root is
+// the certified data of the canister:
+//
+//   fork(labeled("balances", trie of account -> balance),
+//        labeled("transactions", trie of index -> sha256(record)))
+//
+// Both tries live in stable memory, so upgrades leave them as they are. A key
+// goes to the bucket named by the first 16 bits of its sha256. Every level of
+// a trie is fork(labeled([0], left), labeled([1], right)) over the next bit
+// of the bucket, and a bucket is a balanced fork of labeled(key, leaf(value))
+// in key order, so the path of a balance is ["balances", bit 0, .., bit 15,
+// account]. Subtrees without entries hash the same at a given level, only the
+// nodes that differ from them are stored.
+//
+// Accounts are keyed by their stored form, balances are LEB128 nats and
+// indices big endian u64s. Records are hashed over their candid encoding.
+// Accounts without a balance and archived records are proven absent.
+use crate::storage;
+use candid::types::number::Nat;
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, leaf_hash, Hash, HashTree};
+use ic_kit::ic;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use token::{Account, OpRecord};
+
+const BALANCES_LABEL: &[u8] = b"balances";
+const TRANSACTIONS_LABEL: &[u8] = b"transactions";
+
+const BALANCES: u8 = 0;
+const TRANSACTIONS: u8 = 1;
+
+const BUCKET_BITS: usize = 16;
+const ZERO: &[u8] = &[0];
+const ONE: &[u8] = &[1];
+
+thread_local! {
+    // hash of a trie node without entries below it, by its height above the
+    // buckets
+    static EMPTY_HASHES: [Hash; BUCKET_BITS + 1] = {
+        let mut hashes = [HashTree::Empty.reconstruct(); BUCKET_BITS + 1];
+        for level in 1..=BUCKET_BITS {
+            hashes[level] = branch_hash(&hashes[level - 1], &hashes[level - 1]);
+        }
+        hashes
+    };
+}
+
+pub fn record_hash(record: &OpRecord) -> Hash {
+    Sha256::digest(candid::encode_one(record).expect("failed to encode op record")).into()
+}
+
+fn nat_bytes(value: &Nat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    value.encode(&mut bytes).expect("failed to encode nat");
+    bytes
+}
+
+fn index_key(index: usize) -> Vec<u8> {
+    (index as u64).to_be_bytes().to_vec()
+}
+
+fn bucket_of(key: &[u8]) -> u16 {
+    let hash = Sha256::digest(key);
+    u16::from_be_bytes([hash[0], hash[1]])
+}
+
+// Nodes are numbered like a heap, the root is 1 and the children of n are 2n
+// and 2n + 1, which puts bucket b at 2^16 + b.
+fn bucket_node(bucket: u16) -> u32 {
+    (1 << BUCKET_BITS) + bucket as u32
+}
+
+fn node_level(node: u32) -> usize {
+    BUCKET_BITS - (31 - node.leading_zeros() as usize)
+}
+
+fn branch_hash(left: &Hash, right: &Hash) -> Hash {
+    fork_hash(&labeled_hash(ZERO, left), &labeled_hash(ONE, right))
+}
+
+fn empty_hash(node: u32) -> Hash {
+    EMPTY_HASHES.with(|h| h[node_level(node)])
+}
+
+fn node_hash(tree: u8, node: u32) -> Hash {
+    storage::certified_node(tree, node).unwrap_or_else(|| empty_hash(node))
+}
+
+fn set_node_hash(tree: u8, node: u32, hash: Hash) {
+    let stored = if hash == empty_hash(node) { None } else { Some(hash) };
+    storage::set_certified_node(tree, node, stored);
+}
+
+// The entries of a bucket, only the value of `reveal` is included and the
+// others are pruned to their leaf hash.
+fn bucket_tree<'a>(entries: &'a [(Vec<u8>, Vec<u8>)], reveal: Option<&[u8]>) -> HashTree<'a> {
+    match entries {
+        [] => HashTree::Empty,
+        [(key, value)] if reveal == Some(key.as_slice()) => {
+            labeled(key, HashTree::Leaf(Cow::Borrowed(value)))
+        }
+        [(key, value)] => labeled(key, HashTree::Pruned(leaf_hash(value))),
+        _ => {
+            let (left, right) = entries.split_at(entries.len() / 2);
+            fork(bucket_tree(left, reveal), bucket_tree(right, reveal))
+        }
+    }
+}
+
+// Sets or removes `key` and rehashes the path from its bucket to the root.
+fn update(tree: u8, key: Vec<u8>, value: Option<Vec<u8>>) {
+    let bucket = bucket_of(&key);
+    storage::set_certified_entry(tree, bucket, key, value);
+    let mut node = bucket_node(bucket);
+    let mut hash = bucket_tree(&storage::certified_bucket(tree, bucket), None).reconstruct();
+    set_node_hash(tree, node, hash);
+    while node > 1 {
+        let sibling = node_hash(tree, node ^ 1);
+        hash = if node & 1 == 0 {
+            branch_hash(&hash, &sibling)
+        } else {
+            branch_hash(&sibling, &hash)
+        };
+        node /= 2;
+        set_node_hash(tree, node, hash);
+    }
+}
+
+fn root(tree: u8) -> Hash {
+    node_hash(tree, 1)
+}
+
+// The path of `key` in `tree`, `entries` being the content of its bucket.
+fn witness<'a>(tree: u8, key: &[u8], entries: &'a [(Vec<u8>, Vec<u8>)]) -> HashTree<'a> {
+    let mut node = bucket_node(bucket_of(key));
+    let mut witness = bucket_tree(entries, Some(key));
+    while node > 1 {
+        let sibling = HashTree::Pruned(node_hash(tree, node ^ 1));
+        witness = if node & 1 == 0 {
+            fork(labeled(ZERO, witness), labeled(ONE, sibling))
+        } else {
+            fork(labeled(ZERO, sibling), labeled(ONE, witness))
+        };
+        node /= 2;
+    }
+    witness
+}
+
+pub fn set_balance(account: &Account, value: &Nat) {
+    let value = if *value == 0u64 {
+        None
+    } else {
+        Some(nat_bytes(value))
+    };
+    update(BALANCES, storage::account_bytes(account), value);
+    certify();
+}
+
+pub fn add_record(record: &OpRecord) {
+    update(
+        TRANSACTIONS,
+        index_key(record.index),
+        Some(record_hash(record).to_vec()),
+    );
+    certify();
+}
+
+pub fn remove_record(index: usize) {
+    update(TRANSACTIONS, index_key(index), None);
+    certify();
+}
+
+// Sets every key of `entries`, then hashes each bucket they went to once and
+// each level above those buckets once, from the buckets up to the root.
+fn update_all(tree: u8, entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) {
+    let mut buckets = BTreeSet::new();
+    for (key, value) in entries {
+        let bucket = bucket_of(&key);
+        storage::set_certified_entry(tree, bucket, key, Some(value));
+        buckets.insert(bucket);
+    }
+    let mut level: BTreeMap<u32, Hash> = buckets
+        .into_iter()
+        .map(|bucket| {
+            let hash = bucket_tree(&storage::certified_bucket(tree, bucket), None).reconstruct();
+            set_node_hash(tree, bucket_node(bucket), hash);
+            (bucket_node(bucket), hash)
+        })
+        .collect();
+    for _ in 0..BUCKET_BITS {
+        let mut parents = BTreeMap::new();
+        for (&node, hash) in level.iter() {
+            if parents.contains_key(&(node / 2)) {
+                continue;
+            }
+            let sibling = level
+                .get(&(node ^ 1))
+                .copied()
+                .unwrap_or_else(|| node_hash(tree, node ^ 1));
+            let parent = if node & 1 == 0 {
+                branch_hash(hash, &sibling)
+            } else {
+                branch_hash(&sibling, hash)
+            };
+            set_node_hash(tree, node / 2, parent);
+            parents.insert(node / 2, parent);
+        }
+        level = parents;
+    }
+}
+
+/// Builds both tries from the holders and the local records, for canisters
+/// upgraded from a version that did not certify them.
+pub fn rebuild() {
+    update_all(
+        BALANCES,
+        storage::holders(0, usize::MAX)
+            .into_iter()
+            .map(|(account, balance)| (storage::account_bytes(&account), nat_bytes(&balance))),
+    );
+    update_all(
+        TRANSACTIONS,
+        storage::ops(0, usize::MAX)
+            .into_iter()
+            .map(|record| (index_key(record.index), record_hash(&record).to_vec())),
+    );
+    certify();
+}
+
+pub fn root_hash() -> Hash {
+    fork_hash(
+        &labeled_hash(BALANCES_LABEL, &root(BALANCES)),
+        &labeled_hash(TRANSACTIONS_LABEL, &root(TRANSACTIONS)),
+    )
+}
+
+pub fn certify() {
+    ic::set_certified_data(&root_hash());
+}
+
+pub fn balance_witness(account: &Account) -> Vec<u8> {
+    let key = storage::account_bytes(account);
+    let entries = storage::certified_bucket(BALANCES, bucket_of(&key));
+    serialize(fork(
+        labeled(BALANCES_LABEL, witness(BALANCES, &key, &entries)),
+        HashTree::Pruned(labeled_hash(TRANSACTIONS_LABEL, &root(TRANSACTIONS))),
+    ))
+}
+
+pub fn transaction_witness(index: usize) -> Vec<u8> {
+    let key = index_key(index);
+    let entries = storage::certified_bucket(TRANSACTIONS, bucket_of(&key));
+    serialize(fork(
+        HashTree::Pruned(labeled_hash(BALANCES_LABEL, &root(BALANCES))),
+        labeled(TRANSACTIONS_LABEL, witness(TRANSACTIONS, &key, &entries)),
+    ))
+}
+
+fn serialize(tree: HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+    serializer
+        .self_describe()
+        .expect("failed to encode witness");
+    tree.serialize(&mut serializer)
+        .expect("failed to encode witness");
+    serializer.into_inner()
+}
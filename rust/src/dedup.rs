@@ -0,0 +1,82 @@
+/**
+* Module     : dedup.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Transactions carrying a `created_at_time` are deduplicated the ICRC-1 way:
+// a call with the same caller, operation, accounts, amount, memo and
+// created_at_time as one recorded within the window is rejected.
+use crate::{storage, TxError, ERR_MEMO_TOO_LONG};
+use candid::types::number::Nat;
+use ic_kit::{ic, Principal};
+use sha2::{Digest, Sha256};
+use token::{Account, Operation};
+
+pub const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+pub const MAX_MEMO_LENGTH: usize = 32;
+
+pub struct Transaction<'a> {
+    pub caller: Principal,
+    pub op: Operation,
+    pub from: &'a Account,
+    pub to: &'a Account,
+    pub amount: &'a Nat,
+    pub memo: &'a Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+impl Transaction<'_> {
+    fn hash(&self, created_at_time: u64) -> [u8; 32] {
+        let bytes = candid::encode_args((
+            self.caller,
+            self.op,
+            self.from,
+            self.to,
+            self.amount,
+            self.memo,
+            created_at_time,
+        ))
+        .expect("failed to encode transaction");
+        Sha256::digest(&bytes).into()
+    }
+}
+
+/// Rejects `tx` if it is too old to be deduplicated, comes from the future or
+/// was already recorded. Returns the hash to `remember` once it went through.
+pub fn check(tx: Transaction) -> Result<Option<[u8; 32]>, TxError> {
+    let now = ic::time();
+    // anything added before this would fail the TooOld check below anyway
+    storage::prune_deduplicated(now.saturating_sub(TX_WINDOW + 2 * PERMITTED_DRIFT));
+    if let Some(memo) = tx.memo {
+        if memo.len() > MAX_MEMO_LENGTH {
+            return Err(TxError::Other {
+                code: ERR_MEMO_TOO_LONG,
+                message: format!("memo must be at most {} bytes", MAX_MEMO_LENGTH),
+            });
+        }
+    }
+    let created_at_time = match tx.created_at_time {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+        return Err(TxError::TooOld);
+    }
+    if created_at_time > now + PERMITTED_DRIFT {
+        return Err(TxError::CreatedInFuture { ledger_time: now });
+    }
+    let hash = tx.hash(created_at_time);
+    if let Some(duplicate_of) = storage::deduplicated(&hash) {
+        return Err(TxError::Duplicate { duplicate_of });
+    }
+    Ok(Some(hash))
+}
+
+pub fn remember(hash: Option<[u8; 32]>, index: usize) {
+    if let Some(hash) = hash {
+        storage::add_deduplicated(hash, index, ic::time());
+    }
+}
@@ -0,0 +1,50 @@
+/**
+* Module     : escrow.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Escrowed funds leave the balance of the sender and wait in their own table
+// until they are released to the receiver or refunded. Before `release_time`
+// only the sender or the arbiter may release or refund, afterwards any party
+// may release and only the arbiter may still refund.
+use candid::{types::number::Nat, CandidType, Deserialize};
+use ic_kit::Principal;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct Escrow {
+    pub id: u64,
+    pub from: Principal,
+    pub to: Principal,
+    pub amount: Nat,
+    pub release_time: u64,
+    pub arbiter: Option<Principal>,
+    pub created_at: u64,
+}
+
+impl Escrow {
+    pub fn parties(&self) -> Vec<Principal> {
+        let mut parties = vec![self.from, self.to];
+        parties.extend(self.arbiter);
+        parties.sort();
+        parties.dedup();
+        parties
+    }
+
+    fn is_arbiter(&self, user: &Principal) -> bool {
+        self.arbiter.as_ref() == Some(user)
+    }
+
+    pub fn can_release(&self, user: &Principal, now: u64) -> bool {
+        if now >= self.release_time {
+            self.parties().contains(user)
+        } else {
+            *user == self.from || self.is_arbiter(user)
+        }
+    }
+
+    pub fn can_refund(&self, user: &Principal, now: u64) -> bool {
+        self.is_arbiter(user) || (*user == self.from && now < self.release_time)
+    }
+}
@@ -0,0 +1,236 @@
+/**
+* Module     : fees.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Fees charged per operation. Operations without a model of their own pay the
+// flat `Metadata.fee`, exempt principals pay nothing when they make the call.
+// Fees go to `Metadata.fee_to` unless a weighted list of recipients is set,
+// which may send them to a bucket they are later claimed from.
+use crate::{
+    storage, Metadata, TxError, ERR_INVALID_ARGUMENT, ERR_INVALID_SUBACCOUNT, NANOS_PER_DAY,
+};
+use candid::{types::number::Nat, CandidType, Deserialize};
+use ic_kit::{ic, Principal};
+use token::{Account, Operation};
+
+const MAX_BPS: u64 = 10_000;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum FeeModel {
+    Flat(Nat),
+    // basis points of the amount, clamped to [min, max]
+    Percentage {
+        bps: u64,
+        min: Nat,
+        max: Option<Nat>,
+    },
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub transfer: Option<FeeModel>,
+    pub transfer_from: Option<FeeModel>,
+    pub approve: Option<FeeModel>,
+    pub exempt: Vec<Principal>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum FeeDestination {
+    Account(Account),
+    // taken out of the total supply
+    Burn,
+    // accrued apart from all balances until claimed
+    Bucket,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct FeeRecipient {
+    pub destination: FeeDestination,
+    pub weight: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, Default, PartialEq)]
+pub struct FeeRecipients(pub Vec<FeeRecipient>);
+
+impl FeeModel {
+    fn fee(&self, amount: &Nat) -> Nat {
+        match self {
+            FeeModel::Flat(fee) => fee.clone(),
+            FeeModel::Percentage { bps, min, max } => {
+                let fee = amount.clone() * *bps / MAX_BPS;
+                let fee = if fee < *min { min.clone() } else { fee };
+                match max {
+                    Some(max) if fee > *max => max.clone(),
+                    _ => fee,
+                }
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), TxError> {
+        if let FeeModel::Percentage { bps, min, max } = self {
+            if *bps > MAX_BPS {
+                return Err(invalid(format!("bps must be at most {}", MAX_BPS)));
+            }
+            if matches!(max, Some(max) if max < min) {
+                return Err(invalid("max fee is below the min fee".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> TxError {
+    TxError::Other {
+        code: ERR_INVALID_ARGUMENT,
+        message,
+    }
+}
+
+pub fn schedule() -> FeeSchedule {
+    storage::fee_schedule()
+}
+
+pub fn set_schedule(schedule: FeeSchedule) -> Result<(), TxError> {
+    let models = [
+        &schedule.transfer,
+        &schedule.transfer_from,
+        &schedule.approve,
+    ];
+    for model in models.iter().copied().flatten() {
+        model.validate()?;
+    }
+    storage::set_fee_schedule(schedule);
+    Ok(())
+}
+
+/// Fee of `op` on `amount` when called by `caller`.
+pub fn fee(op: Operation, caller: &Principal, amount: &Nat) -> Nat {
+    let schedule = schedule();
+    if schedule.exempt.contains(caller) {
+        return Nat::from(0);
+    }
+    let model = match op {
+        Operation::Transfer => schedule.transfer,
+        Operation::TransferFrom => schedule.transfer_from,
+        Operation::Approve => schedule.approve,
+        _ => None,
+    };
+    match model {
+        Some(model) => model.fee(amount),
+        None => ic::get::<Metadata>().fee.clone(),
+    }
+}
+
+/// The transfer fee reported by `icrc1_fee`, for callers that are not exempt.
+/// A percentage model has no single fee, its fee on a zero amount is reported
+/// and transfers of other amounts should leave `fee` unset.
+pub fn icrc1_fee() -> Nat {
+    match schedule().transfer {
+        Some(model) => model.fee(&Nat::from(0)),
+        None => ic::get::<Metadata>().fee.clone(),
+    }
+}
+
+pub fn recipients() -> Vec<FeeRecipient> {
+    storage::fee_recipients().0
+}
+
+pub fn set_recipients(recipients: Vec<FeeRecipient>) -> Result<(), TxError> {
+    if recipients.iter().any(|r| r.weight == 0) {
+        return Err(invalid("weights must be positive".to_string()));
+    }
+    // `split` divides by the total weight
+    if recipients
+        .iter()
+        .try_fold(0u64, |total, r| total.checked_add(r.weight))
+        .is_none()
+    {
+        return Err(invalid(
+            "the weights add up to more than a nat64".to_string(),
+        ));
+    }
+    let recipients = recipients
+        .into_iter()
+        .map(|r| {
+            Ok(FeeRecipient {
+                destination: match r.destination {
+                    FeeDestination::Account(a) => {
+                        FeeDestination::Account(Account::try_new(a.owner, a.subaccount).map_err(
+                            |message| TxError::Other {
+                                code: ERR_INVALID_SUBACCOUNT,
+                                message,
+                            },
+                        )?)
+                    }
+                    destination => destination,
+                },
+                weight: r.weight,
+            })
+        })
+        .collect::<Result<_, TxError>>()?;
+    storage::set_fee_recipients(FeeRecipients(recipients));
+    Ok(())
+}
+
+/// Splits `fee` by weight, the rounding remainder goes to the first recipient.
+pub fn split(recipients: &[FeeRecipient], fee: &Nat) -> Vec<(FeeDestination, Nat)> {
+    let total_weight: u64 = recipients.iter().map(|r| r.weight).sum();
+    let mut legs: Vec<(FeeDestination, Nat)> = recipients
+        .iter()
+        .map(|r| (r.destination.clone(), fee.clone() * r.weight / total_weight))
+        .collect();
+    let assigned = legs
+        .iter()
+        .fold(Nat::from(0), |sum, (_, amount)| sum + amount.clone());
+    if let Some((_, first)) = legs.first_mut() {
+        *first += fee.clone() - assigned;
+    }
+    legs
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct DailyFees {
+    pub day: u64,
+    pub op: Operation,
+    pub collected: Nat,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct FeeStats {
+    // fees in the bucket that can still be claimed
+    pub accrued: Nat,
+    pub collected: Vec<DailyFees>,
+}
+
+const FEE_OPS: [Operation; 3] = [
+    Operation::Transfer,
+    Operation::TransferFrom,
+    Operation::Approve,
+];
+
+/// Adds `fee` to the fees collected today on `op`.
+pub fn record(op: Operation, fee: Nat) {
+    if let Some(code) = FEE_OPS.iter().position(|o| *o == op) {
+        storage::add_fee_stat(ic::time() / NANOS_PER_DAY, code as u8, fee);
+    }
+}
+
+/// Fees collected on the `days` days from `start_day`, counted since the epoch.
+pub fn stats(start_day: u64, days: u64) -> FeeStats {
+    let collected = storage::fee_stats(start_day, start_day.saturating_add(days))
+        .into_iter()
+        .map(|(day, code, collected)| DailyFees {
+            day,
+            op: FEE_OPS[code as usize],
+            collected,
+        })
+        .collect();
+    FeeStats {
+        accrued: storage::fee_bucket(),
+        collected,
+    }
+}
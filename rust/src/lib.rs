@@ -0,0 +1,230 @@
+/**
+* Module     : lib.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// History record types, shared with the archive canisters so that archived
+// records keep the exact candid shape served by the token canister.
+use candid::{types::number::Nat, CandidType, Deserialize};
+use ic_kit::Principal;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+#[derive(Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Mint,
+    Burn,
+    Transfer,
+    TransferFrom,
+    Approve,
+    GrantRole(Role),
+    RevokeRole(Role),
+    Pause(PauseFlags),
+    Unpause(PauseFlags),
+    Freeze,
+    Unfreeze,
+    WipeFrozenBalance,
+    // one share of a fee split across several recipients
+    Fee,
+    // withdrawal out of the accrued fees
+    ClaimFees,
+    // vested tokens unlocked by their holder
+    Release,
+    // escrow with the given id created, released to its receiver or refunded
+    CreateEscrow(u64),
+    ReleaseEscrow(u64),
+    RefundEscrow(u64),
+    Stake,
+    // starts unbonding, the tokens are withdrawn by WithdrawUnstaked
+    Unstake,
+    WithdrawUnstaked,
+    // steps of an ownership transfer, from the current owner to the proposed one
+    ProposeOwner,
+    AcceptOwnership,
+    CancelOwnershipTransfer,
+}
+
+/// Operations halted while the canister is paused, each one on its own.
+#[derive(Deserialize, CandidType, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PauseFlags {
+    pub transfer: bool,
+    pub transfer_from: bool,
+    pub approve: bool,
+    pub mint: bool,
+    pub burn: bool,
+}
+
+impl PauseFlags {
+    pub fn is_paused(&self, op: Operation) -> bool {
+        match op {
+            Operation::Transfer => self.transfer,
+            Operation::TransferFrom => self.transfer_from,
+            Operation::Approve => self.approve,
+            Operation::Mint => self.mint,
+            Operation::Burn => self.burn,
+            _ => false,
+        }
+    }
+}
+
+/// Privileges that can be handed out by the owner, the owner holds all of them.
+#[derive(Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Owner,
+    Minter,
+    FeeAdmin,
+    MetadataAdmin,
+    Pauser,
+}
+
+#[derive(Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum TransactionStatus {
+    Succeeded,
+    Inprogress,
+    Failed,
+}
+
+pub type Subaccount = Vec<u8>;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    /// The all-zero subaccount is the default one, it is always kept as `None`
+    /// so that both spellings refer to the same balance. Fails on subaccounts
+    /// that are not 32 bytes long.
+    pub fn try_new(owner: Principal, subaccount: Option<Subaccount>) -> Result<Self, String> {
+        if let Some(s) = subaccount.as_ref().filter(|s| s.len() != 32) {
+            return Err(format!("subaccount must be 32 bytes, found {}", s.len()));
+        }
+        let subaccount = subaccount.filter(|s| s.iter().any(|b| *b != 0));
+        Ok(Account { owner, subaccount })
+    }
+
+    /// Same as `try_new` for subaccounts known to be valid, traps otherwise.
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Self::try_new(owner, subaccount).expect("invalid subaccount")
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct OpRecord {
+    pub caller: Option<Principal>,
+    pub op: Operation,
+    pub index: usize,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Nat,
+    pub timestamp: u64,
+    pub status: TransactionStatus,
+    pub memo: Option<Vec<u8>>,
+    // as given by the caller, used to deduplicate retried calls
+    pub created_at_time: Option<u64>,
+}
+
+// Records written before balances were keyed by account, still found in
+// stable memory, archives and heap upgrade payloads.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct OpRecordV0 {
+    pub caller: Option<Principal>,
+    pub op: Operation,
+    pub index: usize,
+    pub from: Principal,
+    pub to: Principal,
+    pub amount: Nat,
+    pub fee: Nat,
+    pub timestamp: u64,
+    pub status: TransactionStatus,
+}
+
+impl From<OpRecordV0> for OpRecord {
+    fn from(r: OpRecordV0) -> Self {
+        OpRecord {
+            caller: r.caller,
+            op: r.op,
+            index: r.index,
+            from: r.from.into(),
+            to: r.to.into(),
+            amount: r.amount,
+            fee: r.fee,
+            timestamp: r.timestamp,
+            status: r.status,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+}
+
+impl Storable for OpRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode op record"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap_or_else(|_| {
+            candid::decode_one::<OpRecordV0>(&bytes)
+                .expect("failed to decode op record")
+                .into()
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A record chained to the one before it, its hash is the sha256 of its
+/// candid encoding.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+pub struct Block {
+    pub parent_hash: Option<Vec<u8>>,
+    pub transaction: OpRecord,
+}
+
+impl Block {
+    pub fn encode(&self) -> Vec<u8> {
+        candid::encode_one(self).expect("failed to encode block")
+    }
+}
+
+pub fn block_hash(encoded: &[u8]) -> [u8; 32] {
+    Sha256::digest(encoded).into()
+}
+
+/// Re-derives the chain over consecutive encoded blocks, the first of which
+/// must point at `parent_hash`. Returns the hash of the last block.
+pub fn verify_chain(
+    parent_hash: Option<Vec<u8>>,
+    blocks: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, String> {
+    let mut parent_hash = parent_hash;
+    let mut next_index = None;
+    for encoded in blocks {
+        let block: Block = candid::decode_one(encoded).map_err(|e| e.to_string())?;
+        let index = block.transaction.index;
+        if let Some(next) = next_index.filter(|next| *next != index) {
+            return Err(format!("expected block {}, found block {}", next, index));
+        }
+        if block.parent_hash != parent_hash {
+            return Err(format!("block {} does not point at its parent", index));
+        }
+        parent_hash = Some(block_hash(encoded).to_vec());
+        next_index = Some(index + 1);
+    }
+    Ok(parent_hash)
+}
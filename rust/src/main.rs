@@ -5,13 +5,30 @@
 * Maintainer : Rocklabs <hello@rocklabs.io>
 * Stability  : Experimental
 */
-use candid::{candid_method, CandidType, Deserialize, types::number::Nat};
+use candid::{candid_method, CandidType, Deserialize, types::number::{Int, Nat}};
 use ic_kit::{ic , Principal};
 use ic_cdk_macros::*;
-use std::collections::HashMap;
-use std::iter::FromIterator;
 use std::string::String;
 
+mod archive;
+mod blocks;
+mod certification;
+mod dedup;
+mod escrow;
+mod fees;
+mod staking;
+mod storage;
+mod vesting;
+
+use archive::{ArchiveInfo, ArchiveOptions, Archives};
+use fees::{FeeDestination, FeeRecipient, FeeSchedule, FeeStats};
+use escrow::Escrow;
+use staking::{Stake, StakingPool, Unbonding};
+use vesting::{Vesting, VestingSchedule};
+use token::{
+    Account, OpRecord, OpRecordV0, Operation, PauseFlags, Role, Subaccount, TransactionStatus,
+};
+
 #[derive(Deserialize, CandidType, Clone, Debug)]
 struct Metadata {
     logo: String,
@@ -22,6 +39,10 @@ struct Metadata {
     owner: Principal,
     fee: Nat,
     fee_to: Principal,
+    // proposed owner that has yet to accept, missing from older payloads
+    pending_owner: Option<Principal>,
+    // cap on the total supply enforced by mint, None means uncapped
+    max_supply: Option<Nat>,
 }
 
 #[derive(Deserialize, CandidType, Clone, Debug)]
@@ -33,8 +54,13 @@ struct TokenInfo {
     deploy_time: u64,
     holder_number: usize,
     cycles: u64,
+    paused: PauseFlags,
 }
 
+// Kept apart from the op log since the genesis record eventually moves to an archive.
+#[derive(Default)]
+struct DeployTime(u64);
+
 impl Default for Metadata {
     fn default() -> Self {
         Metadata {
@@ -46,84 +72,424 @@ impl Default for Metadata {
             owner: Principal::anonymous(),
             fee: Nat::from(0),
             fee_to: Principal::anonymous(),
+            pending_owner: None,
+            max_supply: None,
         }
     }
 }
 
-type Balances = HashMap<Principal, Nat>;
-type Allowances = HashMap<Principal, HashMap<Principal, Nat>>;
-type Ops = Vec<OpRecord>;
+// Layout written by canisters predating the versioned payload, it carries no history.
+#[derive(Deserialize, CandidType)]
+struct UpgradePayloadV0 {
+    metadata: Metadata,
+    balance: Vec<(Principal, Nat)>,
+    allow: Vec<(Principal, Vec<(Principal, Nat)>)>,
+}
 
 #[derive(Deserialize, CandidType)]
-struct UpgradePayload {
+struct UpgradePayloadV1 {
     metadata: Metadata,
     balance: Vec<(Principal, Nat)>,
     allow: Vec<(Principal, Vec<(Principal, Nat)>)>,
+    ops: Vec<OpRecordV0>,
 }
 
-#[derive(CandidType, Clone, Copy, Debug, PartialEq)]
-enum Operation {
-    Mint,
-    Burn,
-    Transfer,
-    TransferFrom,
-    Approve,
+// Balances, allowances and the op log live in stable structures, only the
+// heap state is carried over from here on.
+#[derive(Deserialize, CandidType)]
+struct UpgradePayloadV3 {
+    metadata: Metadata,
+    deploy_time: u64,
+    archive_options: ArchiveOptions,
+    archives: Vec<ArchiveInfo>,
 }
 
-#[derive(CandidType, Clone, Copy, Debug, PartialEq)]
-enum TransactionStatus {
-    Succeeded,
-    Inprogress,
-    Failed,
+#[derive(Deserialize, CandidType)]
+struct UpgradePayloadV5 {
+    metadata: Metadata,
+    deploy_time: u64,
+    archive_options: ArchiveOptions,
+    archives: Vec<ArchiveInfo>,
+    paused: PauseFlags,
+    uninstalled_archive: Option<Principal>,
+    // whether the per-user history index covers every local record
+    user_index_built: bool,
 }
 
-#[derive(CandidType, Clone, Debug)]
-struct OpRecord {
-    caller: Option<Principal>,
-    op: Operation,
-    index: usize,
-    from: Principal,
-    to: Principal,
-    amount: Nat,
-    fee: Nat,
-    timestamp: u64,
-    status: TransactionStatus,
+// Add a new variant here whenever the persisted state changes shape, and keep
+// decoding the old ones in post_upgrade. V2 and V4 were never released, do not
+// reuse their tags.
+#[derive(Deserialize, CandidType)]
+enum UpgradePayload {
+    V1(UpgradePayloadV1),
+    V3(UpgradePayloadV3),
+    V5(UpgradePayloadV5),
 }
 
 #[derive(CandidType, Debug, PartialEq)]
 enum TxError {
+    // returned by the original DIP20 methods instead of InsufficientFunds, so
+    // that their existing clients keep decoding the errors they know
     InsufficientBalance,
     InsufficientAllowance,
     Unauthorized,
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    // the amount is zero or too small for the operation
+    AmountTooSmall,
+    TemporarilyUnavailable,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: usize },
+    // `code` is one of the ERR_ codes below
+    Other { code: u64, message: String },
 }
+
+// Codes of `TxError::Other` and of the ICRC generic errors, the message only
+// adds details. They are listed in spec.md, never renumber them. Code 0 is an
+// ICRC generic error for a TxError variant without an ICRC counterpart.
+const ERR_INVALID_ARGUMENT: u64 = 1;
+const ERR_INVALID_SUBACCOUNT: u64 = 2;
+const ERR_MEMO_TOO_LONG: u64 = 3;
+const ERR_BATCH_TOO_LONG: u64 = 4;
+const ERR_FROZEN: u64 = 5;
+const ERR_NOT_FROZEN: u64 = 6;
+const ERR_MAX_SUPPLY_EXCEEDED: u64 = 7;
+const ERR_MINT_QUOTA_EXHAUSTED: u64 = 8;
+const ERR_NO_SUCH_ESCROW: u64 = 9;
+const ERR_NO_VESTING_SCHEDULE: u64 = 10;
+// nothing has vested, unbonded or been earned yet
+const ERR_NOTHING_TO_CLAIM: u64 = 11;
+// the call would leave a role or freeze as it is
+const ERR_UNCHANGED: u64 = 12;
+const ERR_UNSUPPORTED: u64 = 13;
+const ERR_NO_PENDING_OWNER: u64 = 14;
 type TxReceipt = Result<usize, TxError>;
 
+#[allow(clippy::large_enum_variant)]
+#[derive(CandidType, Debug)]
+enum TransactionResponse {
+    Found(OpRecord),
+    // the record was moved to this archive, ask it instead
+    Archived(ArchiveInfo),
+    // past the end of the history
+    NotFound,
+}
+
+// `witness` is a CBOR encoded hash tree whose root hash is certified by
+// `certificate`, see certification.rs for its layout.
+#[derive(CandidType, Debug)]
+struct CertifiedBalance {
+    balance: Nat,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Debug)]
+struct CertifiedTransaction {
+    transaction: TransactionResponse,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Debug)]
+struct TransactionsResponse {
+    // index of the first entry in `transactions`
+    first_index: usize,
+    transactions: Vec<OpRecord>,
+    // requested ranges that have to be fetched from archives
+    archived: Vec<ArchiveInfo>,
+}
+
+#[derive(CandidType, Debug)]
+struct UserTransactionsResponse {
+    transactions: Vec<OpRecord>,
+    // runs of the user's indices in the page that have to be fetched from
+    // archives
+    archived: Vec<ArchiveInfo>,
+}
+
+#[derive(CandidType, Debug)]
+struct BlocksResponse {
+    // index of the first entry in `blocks`
+    first_index: usize,
+    // candid encoded `Block`s
+    blocks: Vec<Vec<u8>>,
+    // hash of the last block, the parent of the next one
+    tip_hash: Option<Vec<u8>>,
+    archived: Vec<ArchiveInfo>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Debug, PartialEq)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Debug, PartialEq)]
+enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct ApproveArgs {
+    from_subaccount: Option<Subaccount>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Debug, PartialEq)]
+enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct BatchItem {
+    to: Account,
+    amount: Nat,
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Amount a minter may still mint on `day`, counted in days since the epoch.
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+struct MinterQuota {
+    daily_limit: Nat,
+    remaining: Nat,
+    day: u64,
+}
+
+impl MinterQuota {
+    fn today(self) -> Self {
+        let day = ic::time() / NANOS_PER_DAY;
+        if self.day == day {
+            return self;
+        }
+        MinterQuota {
+            remaining: self.daily_limit.clone(),
+            daily_limit: self.daily_limit,
+            day,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct AllowanceArgs {
+    account: Account,
+    spender: Account,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+struct Allowance {
+    allowance: Nat,
+    expires_at: Option<u64>,
+}
+
+impl Allowance {
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(t) if t <= now)
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Subaccount>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(CandidType, Debug, PartialEq)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// The ICRC errors are mapped from the DIP20 ones, anything without an ICRC
+// counterpart becomes a GenericError carrying the DIP20 variant.
+
+impl From<TxError> for TransferError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            TxError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+            TxError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+            TxError::TooOld => TransferError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            TxError::Duplicate { duplicate_of } => TransferError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+            err => {
+                let (error_code, message) = generic_error(err);
+                TransferError::GenericError { error_code, message }
+            }
+        }
+    }
+}
+
+impl From<TxError> for ApproveError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            TxError::InsufficientFunds { balance } => ApproveError::InsufficientFunds { balance },
+            TxError::TemporarilyUnavailable => ApproveError::TemporarilyUnavailable,
+            TxError::TooOld => ApproveError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            TxError::Duplicate { duplicate_of } => ApproveError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+            err => {
+                let (error_code, message) = generic_error(err);
+                ApproveError::GenericError { error_code, message }
+            }
+        }
+    }
+}
+
+impl From<TxError> for TransferFromError {
+    fn from(err: TxError) -> Self {
+        match err {
+            TxError::BadFee { expected_fee } => TransferFromError::BadFee { expected_fee },
+            TxError::InsufficientFunds { balance } => TransferFromError::InsufficientFunds { balance },
+            TxError::TemporarilyUnavailable => TransferFromError::TemporarilyUnavailable,
+            TxError::TooOld => TransferFromError::TooOld,
+            TxError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+            TxError::Duplicate { duplicate_of } => TransferFromError::Duplicate {
+                duplicate_of: Nat::from(duplicate_of),
+            },
+            err => {
+                let (error_code, message) = generic_error(err);
+                TransferFromError::GenericError { error_code, message }
+            }
+        }
+    }
+}
+
+fn generic_error(err: TxError) -> (Nat, String) {
+    match err {
+        TxError::Other { code, message } => (Nat::from(code), message),
+        err => (Nat::from(0), format!("{:?}", err)),
+    }
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+struct StandardRecord {
+    name: String,
+    url: String,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn add_record(
     caller: Option<Principal>,
     op: Operation,
-    from: Principal,
-    to: Principal,
+    from: Account,
+    to: Account,
     amount: Nat,
     fee: Nat,
     timestamp: u64,
     status: TransactionStatus,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
 ) -> usize {
-    let ops = ic::get_mut::<Ops>();
-    let index = ops.len();
-    ops.push(OpRecord {
+    let index = storage::append_op(&OpRecord {
         caller,
         op,
-        index,
+        index: history_size(),
         from,
         to,
         amount,
         fee,
         timestamp,
         status,
+        memo,
+        created_at_time,
     });
+    let record = storage::op(index).unwrap();
+    index_record(&record);
+    blocks::append(&record);
+    certification::add_record(&record);
+    archive::maybe_archive();
     index
 }
 
+// Adds the record to the history of everyone taking part in it.
+fn index_record(record: &OpRecord) {
+    let mut users = vec![record.from.owner, record.to.owner];
+    users.extend(record.caller);
+    users.sort();
+    users.dedup();
+    for user in users {
+        storage::add_user_op(user, record.index);
+    }
+}
+
+// Accounts passed in by callers, whose subaccounts may be malformed.
+fn _account(owner: Principal, subaccount: Option<Subaccount>) -> Result<Account, TxError> {
+    Account::try_new(owner, subaccount).map_err(|message| TxError::Other {
+        code: ERR_INVALID_SUBACCOUNT,
+        message,
+    })
+}
+
+// Fees accrued in the bucket are recorded as going to the token canister.
+fn fee_bucket_account() -> Account {
+    Account::from(ic::id())
+}
+
+// Mints and burns are recorded from/to the management canister, which can never
+// be a caller.
+fn minting_account() -> Account {
+    Account::from(Principal::management_canister())
+}
+
 #[init]
 #[candid_method(init)]
 fn init(
@@ -143,159 +509,317 @@ fn init(
     metadata.total_supply = total_supply.clone();
     metadata.owner = owner;
     metadata.fee = fee;
-    let balances = ic::get_mut::<Balances>();
-    balances.insert(owner, total_supply.clone());
+    *ic::get_mut::<DeployTime>() = DeployTime(ic::time());
+    _set_balance(owner.into(), total_supply.clone());
     let _ = add_record(
         Some(owner),
         Operation::Mint,
-        Principal::from_text("aaaaa-aa").unwrap(),
-        owner,
+        minting_account(),
+        owner.into(),
         total_supply,
         Nat::from(0),
         ic::time(),
         TransactionStatus::Succeeded,
+        None,
+        None,
     );
 }
 
-fn _transfer(from: Principal, to: Principal, value: Nat) {
-    let balances = ic::get_mut::<Balances>();
-    let from_balance = balance_of(from);
-    let from_balance_new = from_balance - value.clone();
-    if from_balance_new != 0 {
-        balances.insert(from, from_balance_new);
-    } else {
-        balances.remove(&from);
-    }
-    let to_balance = balance_of(to);
-    let to_balance_new = to_balance + value;
-    if to_balance_new != 0 {
-        balances.insert(to, to_balance_new);
-    }
+fn _transfer(from: &Account, to: &Account, value: Nat) {
+    let from_balance = _balance_of(from);
+    _set_balance(from.clone(), from_balance - value.clone());
+    let to_balance = _balance_of(to);
+    _set_balance(to.clone(), to_balance + value);
 }
 
-fn _charge_fee(user: Principal, fee_to: Principal, fee: Nat) {
-    let metadata = ic::get::<Metadata>();
-    if metadata.fee > Nat::from(0) {
-        _transfer(user, fee_to, fee);
+fn _set_balance(account: Account, value: Nat) {
+    certification::set_balance(&account, &value);
+    storage::set_balance(account, value);
+}
+
+// Without recipients the whole fee goes to `fee_to` and only shows in the fee
+// of the record charging it, otherwise every share is recorded on its own.
+fn _charge_fee(op: Operation, user: &Account, fee_to: Principal, fee: Nat) {
+    if fee == 0u64 {
+        return;
+    }
+    fees::record(op, fee.clone());
+    let recipients = fees::recipients();
+    if recipients.is_empty() {
+        _transfer(user, &fee_to.into(), fee);
+        return;
+    }
+    for (destination, amount) in fees::split(&recipients, &fee) {
+        if amount == 0u64 {
+            continue;
+        }
+        let to = match destination {
+            FeeDestination::Account(to) => {
+                _transfer(user, &to, amount.clone());
+                to
+            }
+            FeeDestination::Burn => {
+                let balance = _balance_of(user);
+                _set_balance(user.clone(), balance - amount.clone());
+                ic::get_mut::<Metadata>().total_supply -= amount.clone();
+                minting_account()
+            }
+            FeeDestination::Bucket => {
+                let balance = _balance_of(user);
+                _set_balance(user.clone(), balance - amount.clone());
+                storage::set_fee_bucket(storage::fee_bucket() + amount.clone());
+                fee_bucket_account()
+            }
+        };
+        add_record(
+            None,
+            Operation::Fee,
+            user.clone(),
+            to,
+            amount,
+            Nat::from(0),
+            ic::time(),
+            TransactionStatus::Succeeded,
+            None,
+            None,
+        );
     }
 }
 
-#[update(name = "transfer")]
-#[candid_method(update)]
-fn transfer(to: Principal, value: Nat) -> TxReceipt {
-    let from = ic::caller();
+// The DIP20 methods below act on the caller's default subaccount, these do the
+// actual work for any pair of accounts.
+
+fn _send(
+    from: Account,
+    to: Account,
+    value: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> TxReceipt {
+    _check_paused(Operation::Transfer)?;
+    _check_frozen(&[from.owner, to.owner])?;
+    let dedup = dedup::check(dedup::Transaction {
+        caller: from.owner,
+        op: Operation::Transfer,
+        from: &from,
+        to: &to,
+        amount: &value,
+        memo: &memo,
+        created_at_time,
+    })?;
     let metadata = ic::get::<Metadata>();
-    if balance_of(from) < value.clone() + metadata.fee.clone() {
-        return Err(TxError::InsufficientBalance);
+    let fee = fees::fee(Operation::Transfer, &from.owner, &value);
+    let balance = _spendable_balance(&from);
+    if balance < value.clone() + fee.clone() {
+        return Err(TxError::InsufficientFunds { balance });
     }
-    _charge_fee(from, metadata.fee_to, metadata.fee.clone());
-    _transfer(from, to, value.clone());
+    _charge_fee(Operation::Transfer, &from, metadata.fee_to, fee.clone());
+    _transfer(&from, &to, value.clone());
     let txid = add_record(
         None,
         Operation::Transfer,
         from,
         to,
         value,
-        metadata.fee.clone(),
+        fee,
         ic::time(),
         TransactionStatus::Succeeded,
+        memo,
+        created_at_time,
     );
+    dedup::remember(dedup, txid);
     Ok(txid)
 }
 
-#[update(name = "transferFrom")]
-#[candid_method(update, rename = "transferFrom")]
-fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
-    let owner = ic::caller();
-    let from_allowance = allowance(from, owner);
+fn _transfer_from(
+    spender: Account,
+    from: Account,
+    to: Account,
+    value: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> TxReceipt {
+    _check_paused(Operation::TransferFrom)?;
+    _check_frozen(&[spender.owner, from.owner, to.owner])?;
+    let dedup = dedup::check(dedup::Transaction {
+        caller: spender.owner,
+        op: Operation::TransferFrom,
+        from: &from,
+        to: &to,
+        amount: &value,
+        memo: &memo,
+        created_at_time,
+    })?;
+    let from_allowance = _allowance(&from, &spender);
     let metadata = ic::get::<Metadata>();
-    if from_allowance < value.clone() + metadata.fee.clone() {
+    let fee = fees::fee(Operation::TransferFrom, &spender.owner, &value);
+    if from_allowance.allowance < value.clone() + fee.clone() {
         return Err(TxError::InsufficientAllowance);
-    } 
-    let from_balance = balance_of(from);
-    if from_balance < value.clone() + metadata.fee.clone() {
-        return Err(TxError::InsufficientBalance);
-    }
-    _charge_fee(from, metadata.fee_to, metadata.fee.clone());
-    _transfer(from, to, value.clone());
-    let allowances = ic::get_mut::<Allowances>();
-    match allowances.get(&from) {
-        Some(inner) => {
-            let result = inner.get(&owner).unwrap().clone();
-            let mut temp = inner.clone();
-            if result.clone() - value.clone() - metadata.fee.clone() != 0 {
-                temp.insert(owner, result - value.clone() - metadata.fee.clone());
-                allowances.insert(from, temp);
-            } else {
-                temp.remove(&owner);
-                if temp.len() == 0 {
-                    allowances.remove(&from);
-                } else {
-                    allowances.insert(from, temp);
-                }
-            }
-        }
-        None => {
-            assert!(false);
-        }
     }
+    let from_balance = _spendable_balance(&from);
+    if from_balance < value.clone() + fee.clone() {
+        return Err(TxError::InsufficientFunds {
+            balance: from_balance,
+        });
+    }
+    _charge_fee(Operation::TransferFrom, &from, metadata.fee_to, fee.clone());
+    _transfer(&from, &to, value.clone());
+    storage::set_allowance(
+        from.clone(),
+        spender.clone(),
+        Allowance {
+            allowance: from_allowance.allowance - value.clone() - fee.clone(),
+            expires_at: from_allowance.expires_at,
+        },
+    );
     let txid = add_record(
-        Some(owner),
+        Some(spender.owner),
         Operation::TransferFrom,
         from,
         to,
         value,
-        metadata.fee.clone(),
+        fee,
+        ic::time(),
+        TransactionStatus::Succeeded,
+        memo,
+        created_at_time,
+    );
+    dedup::remember(dedup, txid);
+    Ok(txid)
+}
+
+// Burns out of `from`, spending the allowance of `spender` unless it is `from`.
+fn _burn(
+    spender: Account,
+    from: Account,
+    amount: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> TxReceipt {
+    _check_paused(Operation::Burn)?;
+    _check_frozen(&[spender.owner, from.owner])?;
+    let to = minting_account();
+    let dedup = dedup::check(dedup::Transaction {
+        caller: spender.owner,
+        op: Operation::Burn,
+        from: &from,
+        to: &to,
+        amount: &amount,
+        memo: &memo,
+        created_at_time,
+    })?;
+    let metadata = ic::get_mut::<Metadata>();
+    let from_allowance = _allowance(&from, &spender);
+    if spender != from && from_allowance.allowance < amount {
+        return Err(TxError::InsufficientAllowance);
+    }
+    let spendable = _spendable_balance(&from);
+    if spendable < amount {
+        return Err(TxError::InsufficientFunds { balance: spendable });
+    }
+    if spender != from {
+        storage::set_allowance(
+            from.clone(),
+            spender.clone(),
+            Allowance {
+                allowance: from_allowance.allowance - amount.clone(),
+                expires_at: from_allowance.expires_at,
+            },
+        );
+    }
+    _set_balance(from.clone(), _balance_of(&from) - amount.clone());
+    metadata.total_supply -= amount.clone();
+    let txid = add_record(
+        Some(spender.owner),
+        Operation::Burn,
+        from,
+        to,
+        amount,
+        Nat::from(0),
         ic::time(),
         TransactionStatus::Succeeded,
+        memo,
+        created_at_time,
     );
+    dedup::remember(dedup, txid);
     Ok(txid)
 }
 
+// The DIP20 spec only knows InsufficientBalance.
+fn _dip20(receipt: TxReceipt) -> TxReceipt {
+    receipt.map_err(|err| match err {
+        TxError::InsufficientFunds { .. } => TxError::InsufficientBalance,
+        err => err,
+    })
+}
+
+#[update(name = "transfer")]
+#[candid_method(update)]
+fn transfer(
+    to: Principal,
+    value: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> TxReceipt {
+    _dip20(_send(ic::caller().into(), to.into(), value, memo, created_at_time))
+}
+
+#[update(name = "transferFrom")]
+#[candid_method(update, rename = "transferFrom")]
+fn transfer_from(
+    from: Principal,
+    to: Principal,
+    value: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> TxReceipt {
+    _dip20(_transfer_from(
+        ic::caller().into(),
+        from.into(),
+        to.into(),
+        value,
+        memo,
+        created_at_time,
+    ))
+}
+
 #[update(name = "approve")]
 #[candid_method(update)]
 fn approve(spender: Principal, value: Nat) -> TxReceipt {
-    let owner = ic::caller();
+    _dip20(_approve(spender, value))
+}
+
+fn _approve(spender: Principal, value: Nat) -> TxReceipt {
+    _check_paused(Operation::Approve)?;
+    _check_frozen(&[ic::caller(), spender])?;
+    let owner = Account::from(ic::caller());
     let metadata = ic::get::<Metadata>();
-    if balance_of(owner) < metadata.fee.clone() {
-        return Err(TxError::InsufficientBalance);
-    }
-    _charge_fee(owner, metadata.fee_to, metadata.fee.clone());
-    let v = value.clone() + metadata.fee.clone();
-    let allowances = ic::get_mut::<Allowances>();
-    match allowances.get(&owner) {
-        Some(inner) => {
-            let mut temp = inner.clone();
-            if v != 0 {
-                temp.insert(spender, v.clone());
-                allowances.insert(owner, temp);
-            } else {
-                temp.remove(&spender);
-                if temp.len() == 0 {
-                    allowances.remove(&owner);
-                } else {
-                    allowances.insert(owner, temp);
-                }
-            }
-        }
-        None => {
-            if v != 0 {
-                let mut inner = HashMap::new();
-                inner.insert(spender, v.clone());
-                let allowances = ic::get_mut::<Allowances>();
-                allowances.insert(owner, inner);
-            }
-        }
+    let fee = fees::fee(Operation::Approve, &owner.owner, &value);
+    let balance = _spendable_balance(&owner);
+    if balance < fee {
+        return Err(TxError::InsufficientFunds { balance });
     }
+    _charge_fee(Operation::Approve, &owner, metadata.fee_to, fee.clone());
+    let v = value.clone() + fee.clone();
+    storage::set_allowance(
+        owner.clone(),
+        spender.into(),
+        Allowance {
+            allowance: v.clone(),
+            expires_at: None,
+        },
+    );
     let txid = add_record(
         None,
         Operation::Approve,
         owner,
-        spender,
+        spender.into(),
         v,
-        metadata.fee.clone(),
+        fee,
         ic::time(),
         TransactionStatus::Succeeded,
+        None,
+        None,
     );
     Ok(txid)
 }
@@ -303,25 +827,60 @@ fn approve(spender: Principal, value: Nat) -> TxReceipt {
 #[update(name = "mint")]
 #[candid_method(update, rename = "mint")]
 fn mint(to: Principal, amount: Nat) -> TxReceipt {
-    let caller = ic::caller();
-    let metadata = ic::get_mut::<Metadata>();
-    if caller != metadata.owner {
-        return Err(TxError::Unauthorized);
+    _only_role(Role::Minter)?;
+    _mint(ic::caller(), to.into(), amount)
+}
+
+// Checks `amount` against the max supply and the daily quota of `minter`.
+fn _check_mint_limits(minter: &Principal, amount: &Nat) -> Result<(), TxError> {
+    let metadata = ic::get::<Metadata>();
+    if let Some(max_supply) = &metadata.max_supply {
+        if metadata.total_supply.clone() + amount.clone() > *max_supply {
+            return Err(TxError::Other {
+                code: ERR_MAX_SUPPLY_EXCEEDED,
+                message: format!("minting would exceed the max supply of {}", max_supply),
+            });
+        }
+    }
+    if let Some(quota) = storage::minter_quota(minter).map(MinterQuota::today) {
+        if quota.remaining < *amount {
+            return Err(TxError::Other {
+                code: ERR_MINT_QUOTA_EXHAUSTED,
+                message: format!("{} may only mint {} more today", minter, quota.remaining),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn _mint(minter: Principal, to: Account, amount: Nat) -> TxReceipt {
+    _check_paused(Operation::Mint)?;
+    _check_frozen(&[to.owner])?;
+    _check_mint_limits(&minter, &amount)?;
+    if let Some(quota) = storage::minter_quota(&minter).map(MinterQuota::today) {
+        storage::set_minter_quota(
+            minter,
+            Some(MinterQuota {
+                remaining: quota.remaining - amount.clone(),
+                ..quota
+            }),
+        );
     }
-    let to_balance = balance_of(to);
-    let balances = ic::get_mut::<Balances>();
-    balances.insert(to, to_balance + amount.clone());
-    metadata.total_supply += amount.clone();
-    
+    let to_balance = _balance_of(&to);
+    _set_balance(to.clone(), to_balance + amount.clone());
+    ic::get_mut::<Metadata>().total_supply += amount.clone();
+
     let txid = add_record(
-        Some(caller),
+        Some(minter),
         Operation::Mint,
-        Principal::from_text("aaaaa-aa").unwrap(),
+        minting_account(),
         to,
         amount,
         Nat::from(0),
         ic::time(),
         TransactionStatus::Succeeded,
+        None,
+        None,
     );
     Ok(txid)
 }
@@ -329,93 +888,915 @@ fn mint(to: Principal, amount: Nat) -> TxReceipt {
 #[update(name = "burn")]
 #[candid_method(update, rename = "burn")]
 fn burn(amount: Nat) -> TxReceipt {
-    let caller = ic::caller();
-    let metadata = ic::get_mut::<Metadata>();
-    let caller_balance = balance_of(caller);
-    if caller_balance < amount.clone() {
-        return Err(TxError::InsufficientBalance);
-    }
-    let balances = ic::get_mut::<Balances>();
-    balances.insert(caller, caller_balance - amount.clone());
-    metadata.total_supply -= amount.clone();
-    let txid = add_record(
-        Some(caller),
-        Operation::Burn,
-        caller,
-        Principal::from_text("aaaaa-aa").unwrap(),
-        amount,
-        Nat::from(0),
-        ic::time(),
-        TransactionStatus::Succeeded,
-    );
-    Ok(txid)
-}
-
-#[update(name = "setLogo")]
-#[candid_method(update, rename = "setLogo")]
-fn set_logo(logo: String) {
-    let metadata = ic::get_mut::<Metadata>();
-    assert_eq!(ic::caller(), metadata.owner);
-    metadata.logo = logo;
+    _dip20(_burn(ic::caller().into(), ic::caller().into(), amount, None, None))
 }
 
-#[update(name = "setFee")]
-#[candid_method(update, rename = "setFee")]
-fn set_fee(fee: Nat) {
-    let metadata = ic::get_mut::<Metadata>();
-    assert_eq!(ic::caller(), metadata.owner);
-    metadata.fee = fee;
+// Burns tokens `from` approved to the caller, burns are free like `burn`.
+#[update(name = "burnFrom")]
+#[candid_method(update, rename = "burnFrom")]
+fn burn_from(from: Principal, amount: Nat) -> TxReceipt {
+    _burn(ic::caller().into(), from.into(), amount, None, None)
 }
 
-#[update(name = "setFeeTo")]
-#[candid_method(update, rename = "setFeeTo")]
-fn set_fee_to(fee_to: Principal) {
-    let metadata = ic::get_mut::<Metadata>();
-    assert_eq!(ic::caller(), metadata.owner);
-    metadata.fee_to = fee_to;
+// Longest batch accepted by `batchTransfer` and `batchMint`, it keeps a batch
+// well within the instruction limit of a single message.
+const MAX_BATCH_LEN: usize = 500;
+
+// Every item of a batch is recorded as its own transfer and pays the regular
+// transfer fee, minted items are free like `mint`. The whole batch is checked
+// before the first item is applied, so it either goes through or fails as one.
+#[update(name = "batchTransfer")]
+#[candid_method(update, rename = "batchTransfer")]
+fn batch_transfer(items: Vec<BatchItem>) -> Result<Vec<usize>, TxError> {
+    let from = Account::from(ic::caller());
+    let items = _batch_items(items)?;
+    _check_paused(Operation::Transfer)?;
+    let mut users = vec![from.owner];
+    users.extend(items.iter().map(|item| item.to.owner));
+    _check_frozen(&users)?;
+    let total = items.iter().fold(Nat::from(0), |total, item| {
+        total + item.amount.clone() + fees::fee(Operation::Transfer, &from.owner, &item.amount)
+    });
+    let balance = _spendable_balance(&from);
+    if balance < total {
+        return Err(TxError::InsufficientFunds { balance });
+    }
+    Ok(_apply_batch(items, |item| {
+        _send(from.clone(), item.to, item.amount, None, None)
+    }))
 }
 
-#[update(name = "setOwner")]
-#[candid_method(update, rename = "setOwner")]
-fn set_owner(owner: Principal) {
-    let metadata = ic::get_mut::<Metadata>();
-    assert_eq!(ic::caller(), metadata.owner);
-    metadata.owner = owner;
+#[update(name = "batchMint")]
+#[candid_method(update, rename = "batchMint")]
+fn batch_mint(items: Vec<BatchItem>) -> Result<Vec<usize>, TxError> {
+    let minter = ic::caller();
+    _only_role(Role::Minter)?;
+    let items = _batch_items(items)?;
+    _check_paused(Operation::Mint)?;
+    let users: Vec<Principal> = items.iter().map(|item| item.to.owner).collect();
+    _check_frozen(&users)?;
+    let total = items
+        .iter()
+        .fold(Nat::from(0), |total, item| total + item.amount.clone());
+    _check_mint_limits(&minter, &total)?;
+    Ok(_apply_batch(items, |item| _mint(minter, item.to, item.amount)))
 }
 
-#[query(name = "balanceOf")]
-#[candid_method(query, rename = "balanceOf")]
-fn balance_of(id: Principal) -> Nat {
-    let balances = ic::get::<Balances>();
-    match balances.get(&id) {
-        Some(balance) => balance.clone(),
-        None => Nat::from(0),
+fn _batch_items(items: Vec<BatchItem>) -> Result<Vec<BatchItem>, TxError> {
+    if items.len() > MAX_BATCH_LEN {
+        return Err(TxError::Other {
+            code: ERR_BATCH_TOO_LONG,
+            message: format!("a batch holds at most {} items", MAX_BATCH_LEN),
+        });
     }
+    items
+        .into_iter()
+        .map(|item| {
+            Ok(BatchItem {
+                to: _account(item.to.owner, item.to.subaccount)?,
+                amount: item.amount,
+            })
+        })
+        .collect()
 }
 
-#[query(name = "allowance")]
-#[candid_method(query)]
-fn allowance(owner: Principal, spender: Principal) -> Nat {
-    let allowances = ic::get::<Allowances>();
-    match allowances.get(&owner) {
-        Some(inner) => match inner.get(&spender) {
-            Some(value) => value.clone(),
-            None => Nat::from(0),
-        },
-        None => Nat::from(0),
-    }
+// An item failing once the batch was checked traps, which rolls back the
+// items applied before it.
+fn _apply_batch(
+    items: Vec<BatchItem>,
+    apply: impl FnMut(BatchItem) -> TxReceipt,
+) -> Vec<usize> {
+    items
+        .into_iter()
+        .map(apply)
+        .map(|receipt| {
+            receipt.unwrap_or_else(|err| ic::trap(&format!("batch item failed: {:?}", err)))
+        })
+        .collect()
 }
 
-#[query(name = "getLogo")]
-#[candid_method(query, rename = "getLogo")]
-fn get_logo() -> String {
-    let metadata = ic::get::<Metadata>();
-    metadata.logo.clone()
+// Mints `amount` to `to` locked under a new vesting schedule, next to any
+// schedule `to` already has.
+#[update(name = "mintVested")]
+#[candid_method(update, rename = "mintVested")]
+fn mint_vested(to: Principal, amount: Nat, vesting: Vesting) -> TxReceipt {
+    _only_role(Role::Minter)?;
+    vesting
+        .validate()
+        .map_err(|message| TxError::Other {
+            code: ERR_INVALID_ARGUMENT,
+            message,
+        })?;
+    let txid = _mint(ic::caller(), to.into(), amount.clone())?;
+    let id = storage::next_vesting_id();
+    storage::set_vesting_schedule(
+        to,
+        id,
+        Some(VestingSchedule {
+            id,
+            total: amount,
+            released: Nat::from(0),
+            vesting,
+        }),
+    );
+    Ok(txid)
 }
 
-#[query(name = "name")]
-#[candid_method(query)]
-fn name() -> String {
+// Unlocks everything the caller has vested so far, across all of its
+// schedules, as a single release.
+#[update(name = "release")]
+#[candid_method(update, rename = "release")]
+fn release() -> TxReceipt {
+    let caller = ic::caller();
+    let now = ic::time();
+    let schedules = storage::vesting_schedules(&caller);
+    if schedules.is_empty() {
+        return Err(TxError::Other {
+            code: ERR_NO_VESTING_SCHEDULE,
+            message: format!("{} has no vesting schedule", caller),
+        });
+    }
+    let mut amount = Nat::from(0);
+    for mut schedule in schedules {
+        let vested = schedule.vested(now) - schedule.released.clone();
+        if vested == 0u64 {
+            continue;
+        }
+        amount += vested.clone();
+        schedule.released += vested;
+        if schedule.locked() == 0u64 {
+            storage::set_vesting_schedule(caller, schedule.id, None);
+        } else {
+            storage::set_vesting_schedule(caller, schedule.id, Some(schedule));
+        }
+    }
+    if amount == 0u64 {
+        return Err(TxError::Other {
+            code: ERR_NOTHING_TO_CLAIM,
+            message: "nothing has vested since the last release".to_string(),
+        });
+    }
+    let txid = add_record(
+        Some(caller),
+        Operation::Release,
+        caller.into(),
+        caller.into(),
+        amount,
+        Nat::from(0),
+        now,
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+// Moves `amount` out of the caller's balance into a new escrow for `to`, the
+// regular transfer fee is charged on creation.
+#[update(name = "createEscrow")]
+#[candid_method(update, rename = "createEscrow")]
+fn create_escrow(
+    to: Principal,
+    amount: Nat,
+    release_time: u64,
+    arbiter: Option<Principal>,
+) -> TxReceipt {
+    let caller = ic::caller();
+    let from = Account::from(caller);
+    _check_paused(Operation::Transfer)?;
+    _check_frozen(&[caller, to])?;
+    if amount == 0u64 {
+        return Err(TxError::AmountTooSmall);
+    }
+    if release_time <= ic::time() {
+        return Err(TxError::Other {
+            code: ERR_INVALID_ARGUMENT,
+            message: "the release time must be in the future".to_string(),
+        });
+    }
+    if arbiter == Some(to) || arbiter == Some(caller) {
+        return Err(TxError::Other {
+            code: ERR_INVALID_ARGUMENT,
+            message: "the arbiter cannot be the sender or the receiver".to_string(),
+        });
+    }
+    let metadata = ic::get::<Metadata>();
+    let fee = fees::fee(Operation::Transfer, &caller, &amount);
+    let balance = _spendable_balance(&from);
+    if balance < amount.clone() + fee.clone() {
+        return Err(TxError::InsufficientFunds { balance });
+    }
+    _charge_fee(Operation::Transfer, &from, metadata.fee_to, fee.clone());
+    _set_balance(from.clone(), _balance_of(&from) - amount.clone());
+    let id = storage::next_escrow_id();
+    storage::add_escrow(Escrow {
+        id,
+        from: caller,
+        to,
+        amount: amount.clone(),
+        release_time,
+        arbiter,
+        created_at: ic::time(),
+    });
+    let txid = add_record(
+        Some(caller),
+        Operation::CreateEscrow(id),
+        from,
+        to.into(),
+        amount,
+        fee,
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+fn _close_escrow(id: u64, release: bool) -> TxReceipt {
+    let caller = ic::caller();
+    let now = ic::time();
+    _check_paused(Operation::Transfer)?;
+    let escrow = storage::escrow(id).ok_or_else(|| TxError::Other {
+        code: ERR_NO_SUCH_ESCROW,
+        message: format!("no open escrow with id {}", id),
+    })?;
+    let allowed = if release {
+        escrow.can_release(&caller, now)
+    } else {
+        escrow.can_refund(&caller, now)
+    };
+    if !allowed {
+        return Err(TxError::Unauthorized);
+    }
+    let (to, op) = if release {
+        (escrow.to, Operation::ReleaseEscrow(id))
+    } else {
+        (escrow.from, Operation::RefundEscrow(id))
+    };
+    _check_frozen(&[caller, escrow.from, escrow.to])?;
+    storage::remove_escrow(id);
+    let to = Account::from(to);
+    let to_balance = _balance_of(&to);
+    _set_balance(to.clone(), to_balance + escrow.amount.clone());
+    let txid = add_record(
+        Some(caller),
+        op,
+        escrow.from.into(),
+        to,
+        escrow.amount,
+        Nat::from(0),
+        now,
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+#[update(name = "releaseEscrow")]
+#[candid_method(update, rename = "releaseEscrow")]
+fn release_escrow(id: u64) -> TxReceipt {
+    _close_escrow(id, true)
+}
+
+#[update(name = "refundEscrow")]
+#[candid_method(update, rename = "refundEscrow")]
+fn refund_escrow(id: u64) -> TxReceipt {
+    _close_escrow(id, false)
+}
+
+#[derive(CandidType, Debug, PartialEq)]
+struct StakeInfo {
+    staked: Nat,
+    pending_rewards: Nat,
+    unbonding: Vec<Unbonding>,
+}
+
+// `emission_rate` is in tokens per second, shared by all stakers.
+#[update(name = "setStakingConfig")]
+#[candid_method(update, rename = "setStakingConfig")]
+fn set_staking_config(emission_rate: Nat, unbonding_period: u64) -> Result<(), TxError> {
+    _only_role(Role::Owner)?;
+    let mut pool = storage::staking_pool();
+    // emissions so far accrue at the old rate
+    pool.update(ic::time());
+    pool.emission_rate = emission_rate;
+    pool.unbonding_period = unbonding_period;
+    storage::set_staking_pool(pool);
+    Ok(())
+}
+
+#[update(name = "stake")]
+#[candid_method(update, rename = "stake")]
+fn stake(amount: Nat) -> TxReceipt {
+    let caller = ic::caller();
+    let account = Account::from(caller);
+    _check_paused(Operation::Transfer)?;
+    _check_frozen(&[caller])?;
+    if amount == 0u64 {
+        return Err(TxError::AmountTooSmall);
+    }
+    let balance = _spendable_balance(&account);
+    if balance < amount {
+        return Err(TxError::InsufficientFunds { balance });
+    }
+    let (mut pool, mut stake) = staking::settle(&caller, ic::time());
+    _set_balance(account.clone(), _balance_of(&account) - amount.clone());
+    stake.staked += amount.clone();
+    pool.total_staked += amount.clone();
+    staking::save(caller, pool, stake);
+    let txid = add_record(
+        Some(caller),
+        Operation::Stake,
+        account.clone(),
+        account,
+        amount,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+// Stops `amount` from earning rewards, it can be withdrawn once unbonded.
+#[update(name = "unstake")]
+#[candid_method(update, rename = "unstake")]
+fn unstake(amount: Nat) -> TxReceipt {
+    let caller = ic::caller();
+    let now = ic::time();
+    _check_paused(Operation::Transfer)?;
+    _check_frozen(&[caller])?;
+    if amount == 0u64 {
+        return Err(TxError::AmountTooSmall);
+    }
+    let (mut pool, mut stake) = staking::settle(&caller, now);
+    if stake.staked < amount {
+        return Err(TxError::InsufficientFunds {
+            balance: stake.staked,
+        });
+    }
+    stake.staked -= amount.clone();
+    pool.total_staked -= amount.clone();
+    stake.unbonding.push(Unbonding {
+        amount: amount.clone(),
+        release_at: now + pool.unbonding_period,
+    });
+    staking::save(caller, pool, stake);
+    let txid = add_record(
+        Some(caller),
+        Operation::Unstake,
+        caller.into(),
+        caller.into(),
+        amount,
+        Nat::from(0),
+        now,
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+// Returns every unbonded amount to the caller's balance.
+#[update(name = "withdrawUnstaked")]
+#[candid_method(update, rename = "withdrawUnstaked")]
+fn withdraw_unstaked() -> TxReceipt {
+    let caller = ic::caller();
+    let now = ic::time();
+    _check_paused(Operation::Transfer)?;
+    _check_frozen(&[caller])?;
+    let (pool, mut stake) = staking::settle(&caller, now);
+    let (unbonded, unbonding): (Vec<Unbonding>, Vec<Unbonding>) = stake
+        .unbonding
+        .into_iter()
+        .partition(|u| u.release_at <= now);
+    let amount = unbonded
+        .into_iter()
+        .fold(Nat::from(0), |sum, u| sum + u.amount);
+    if amount == 0u64 {
+        return Err(TxError::Other {
+            code: ERR_NOTHING_TO_CLAIM,
+            message: "nothing has finished unbonding".to_string(),
+        });
+    }
+    stake.unbonding = unbonding;
+    staking::save(caller, pool, stake);
+    let account = Account::from(caller);
+    _set_balance(account.clone(), _balance_of(&account) + amount.clone());
+    let txid = add_record(
+        Some(caller),
+        Operation::WithdrawUnstaked,
+        account.clone(),
+        account,
+        amount,
+        Nat::from(0),
+        now,
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+// Mints the caller's rewards, recorded as a mint by the token canister.
+#[update(name = "claimRewards")]
+#[candid_method(update, rename = "claimRewards")]
+fn claim_rewards() -> TxReceipt {
+    let caller = ic::caller();
+    let (pool, mut stake) = staking::settle(&caller, ic::time());
+    if stake.rewards == 0u64 {
+        return Err(TxError::Other {
+            code: ERR_NOTHING_TO_CLAIM,
+            message: "no rewards to claim".to_string(),
+        });
+    }
+    let txid = _mint(ic::id(), caller.into(), stake.rewards.clone())?;
+    stake.rewards = Nat::from(0);
+    staking::save(caller, pool, stake);
+    Ok(txid)
+}
+
+// Transfers out of one of the caller's subaccounts to any account.
+#[update(name = "transferToAccount")]
+#[candid_method(update, rename = "transferToAccount")]
+fn transfer_to_account(
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    value: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> TxReceipt {
+    _send(
+        _account(ic::caller(), from_subaccount)?,
+        _account(to.owner, to.subaccount)?,
+        value,
+        memo,
+        created_at_time,
+    )
+}
+
+const ALL_ROLES: [Role; 5] = [
+    Role::Owner,
+    Role::Minter,
+    Role::FeeAdmin,
+    Role::MetadataAdmin,
+    Role::Pauser,
+];
+
+fn _has_role(user: &Principal, role: Role) -> bool {
+    *user == ic::get::<Metadata>().owner
+        || storage::has_role(Role::Owner, user)
+        || storage::has_role(role, user)
+}
+
+fn _only_role(role: Role) -> Result<(), TxError> {
+    if !_has_role(&ic::caller(), role) {
+        return Err(TxError::Unauthorized);
+    }
+    Ok(())
+}
+
+fn _set_role(user: Principal, role: Role, granted: bool) -> TxReceipt {
+    let caller = ic::caller();
+    _only_role(Role::Owner)?;
+    if role == Role::Owner && user == ic::get::<Metadata>().owner {
+        return Err(TxError::Other {
+            code: ERR_UNCHANGED,
+            message: "the owner always holds the owner role".to_string(),
+        });
+    }
+    if !storage::set_role(role, user, granted) {
+        return Err(TxError::Other {
+            code: ERR_UNCHANGED,
+            message: format!("{} already {} {:?}", user, if granted { "has" } else { "lacks" }, role),
+        });
+    }
+    let op = if granted {
+        Operation::GrantRole(role)
+    } else {
+        Operation::RevokeRole(role)
+    };
+    let txid = add_record(
+        Some(caller),
+        op,
+        caller.into(),
+        user.into(),
+        Nat::from(0),
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+fn _check_paused(op: Operation) -> Result<(), TxError> {
+    if ic::get::<PauseFlags>().is_paused(op) {
+        return Err(TxError::TemporarilyUnavailable);
+    }
+    Ok(())
+}
+
+fn _set_paused(flags: PauseFlags, paused: bool) -> TxReceipt {
+    let caller = ic::caller();
+    _only_role(Role::Pauser)?;
+    let state = ic::get_mut::<PauseFlags>();
+    // only the flags that are set are changed, the others keep their state
+    state.transfer = if flags.transfer { paused } else { state.transfer };
+    state.transfer_from = if flags.transfer_from { paused } else { state.transfer_from };
+    state.approve = if flags.approve { paused } else { state.approve };
+    state.mint = if flags.mint { paused } else { state.mint };
+    state.burn = if flags.burn { paused } else { state.burn };
+    let op = if paused {
+        Operation::Pause(flags)
+    } else {
+        Operation::Unpause(flags)
+    };
+    let txid = add_record(
+        Some(caller),
+        op,
+        caller.into(),
+        caller.into(),
+        Nat::from(0),
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+#[update(name = "pause")]
+#[candid_method(update, rename = "pause")]
+fn pause(flags: PauseFlags) -> TxReceipt {
+    _set_paused(flags, true)
+}
+
+#[update(name = "unpause")]
+#[candid_method(update, rename = "unpause")]
+fn unpause(flags: PauseFlags) -> TxReceipt {
+    _set_paused(flags, false)
+}
+
+// Frozen principals can neither send, receive, approve nor be approved.
+fn _check_frozen(users: &[Principal]) -> Result<(), TxError> {
+    match users.iter().find(|user| storage::is_frozen(user)) {
+        Some(user) => Err(TxError::Other {
+            code: ERR_FROZEN,
+            message: format!("{} is frozen", user),
+        }),
+        None => Ok(()),
+    }
+}
+
+#[update(name = "freeze")]
+#[candid_method(update, rename = "freeze")]
+fn freeze(user: Principal) -> TxReceipt {
+    _set_frozen(user, true)
+}
+
+#[update(name = "unfreeze")]
+#[candid_method(update, rename = "unfreeze")]
+fn unfreeze(user: Principal) -> TxReceipt {
+    _set_frozen(user, false)
+}
+
+fn _set_frozen(user: Principal, frozen: bool) -> TxReceipt {
+    let caller = ic::caller();
+    _only_role(Role::Owner)?;
+    if !storage::set_frozen(user, frozen) {
+        return Err(TxError::Other {
+            code: ERR_UNCHANGED,
+            message: format!("{} is already {}", user, if frozen { "frozen" } else { "unfrozen" }),
+        });
+    }
+    let op = if frozen {
+        Operation::Freeze
+    } else {
+        Operation::Unfreeze
+    };
+    let txid = add_record(
+        Some(caller),
+        op,
+        caller.into(),
+        user.into(),
+        Nat::from(0),
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+// Burns the whole balance of an account of a frozen principal. Wiping the
+// default account also burns the principal's stake, staked or unbonding, and
+// drops its unclaimed rewards. Open escrows are left alone: none of them can
+// be released or refunded while one of their parties is frozen.
+#[update(name = "wipeFrozenBalance")]
+#[candid_method(update, rename = "wipeFrozenBalance")]
+fn wipe_frozen_balance(account: Account) -> TxReceipt {
+    let caller = ic::caller();
+    _only_role(Role::Owner)?;
+    let account = _account(account.owner, account.subaccount)?;
+    if !storage::is_frozen(&account.owner) {
+        return Err(TxError::Other {
+            code: ERR_NOT_FROZEN,
+            message: format!("{} is not frozen", account.owner),
+        });
+    }
+    let mut amount = _balance_of(&account);
+    _set_balance(account.clone(), Nat::from(0));
+    if account.subaccount.is_none() {
+        // nothing is left to lock
+        for schedule in storage::vesting_schedules(&account.owner) {
+            storage::set_vesting_schedule(account.owner, schedule.id, None);
+        }
+        let (mut pool, stake) = staking::settle(&account.owner, ic::time());
+        pool.total_staked -= stake.staked.clone();
+        amount += stake.staked;
+        for unbonding in stake.unbonding {
+            amount += unbonding.amount;
+        }
+        staking::save(account.owner, pool, Stake::default());
+    }
+    ic::get_mut::<Metadata>().total_supply -= amount.clone();
+    let txid = add_record(
+        Some(caller),
+        Operation::WipeFrozenBalance,
+        account,
+        minting_account(),
+        amount,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+#[update(name = "setMaxSupply")]
+#[candid_method(update, rename = "setMaxSupply")]
+fn set_max_supply(max_supply: Option<Nat>) -> Result<(), TxError> {
+    _only_role(Role::Owner)?;
+    let metadata = ic::get_mut::<Metadata>();
+    if let Some(max_supply) = &max_supply {
+        if *max_supply < metadata.total_supply {
+            return Err(TxError::Other {
+                code: ERR_INVALID_ARGUMENT,
+                message: "max supply is below the total supply".to_string(),
+            });
+        }
+    }
+    metadata.max_supply = max_supply;
+    Ok(())
+}
+
+// Limits what `minter` may mint per day, None lifts the limit.
+#[update(name = "setMinterQuota")]
+#[candid_method(update, rename = "setMinterQuota")]
+fn set_minter_quota(minter: Principal, daily_limit: Option<Nat>) -> Result<(), TxError> {
+    _only_role(Role::Owner)?;
+    let quota = daily_limit.map(|daily_limit| MinterQuota {
+        remaining: daily_limit.clone(),
+        daily_limit,
+        day: ic::time() / NANOS_PER_DAY,
+    });
+    storage::set_minter_quota(minter, quota);
+    Ok(())
+}
+
+#[update(name = "grantRole")]
+#[candid_method(update, rename = "grantRole")]
+fn grant_role(user: Principal, role: Role) -> TxReceipt {
+    _set_role(user, role, true)
+}
+
+#[update(name = "revokeRole")]
+#[candid_method(update, rename = "revokeRole")]
+fn revoke_role(user: Principal, role: Role) -> TxReceipt {
+    _set_role(user, role, false)
+}
+
+#[update(name = "setLogo")]
+#[candid_method(update, rename = "setLogo")]
+fn set_logo(logo: String) -> Result<(), TxError> {
+    _only_role(Role::MetadataAdmin)?;
+    ic::get_mut::<Metadata>().logo = logo;
+    Ok(())
+}
+
+#[update(name = "setFee")]
+#[candid_method(update, rename = "setFee")]
+fn set_fee(fee: Nat) -> Result<(), TxError> {
+    _only_role(Role::FeeAdmin)?;
+    ic::get_mut::<Metadata>().fee = fee;
+    Ok(())
+}
+
+// Per-operation fee models and exemptions, on top of the flat `setFee` fee.
+#[update(name = "setFeeSchedule")]
+#[candid_method(update, rename = "setFeeSchedule")]
+fn set_fee_schedule(schedule: FeeSchedule) -> Result<(), TxError> {
+    _only_role(Role::FeeAdmin)?;
+    fees::set_schedule(schedule)
+}
+
+// Splits every fee by weight, an empty list sends fees to `fee_to` again and
+// a single `Bucket` recipient accrues all of them.
+#[update(name = "setFeeRecipients")]
+#[candid_method(update, rename = "setFeeRecipients")]
+fn set_fee_recipients(recipients: Vec<FeeRecipient>) -> Result<(), TxError> {
+    _only_role(Role::FeeAdmin)?;
+    fees::set_recipients(recipients)
+}
+
+// Moves accrued fees out of the bucket into `to`.
+#[update(name = "claimFees")]
+#[candid_method(update, rename = "claimFees")]
+fn claim_fees(to: Account, amount: Nat) -> TxReceipt {
+    let caller = ic::caller();
+    _only_role(Role::FeeAdmin)?;
+    let to = _account(to.owner, to.subaccount)?;
+    _check_frozen(&[to.owner])?;
+    let accrued = storage::fee_bucket();
+    if accrued < amount {
+        return Err(TxError::InsufficientFunds { balance: accrued });
+    }
+    storage::set_fee_bucket(accrued - amount.clone());
+    let to_balance = _balance_of(&to);
+    _set_balance(to.clone(), to_balance + amount.clone());
+    let txid = add_record(
+        Some(caller),
+        Operation::ClaimFees,
+        fee_bucket_account(),
+        to,
+        amount,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    );
+    Ok(txid)
+}
+
+#[update(name = "setFeeTo")]
+#[candid_method(update, rename = "setFeeTo")]
+fn set_fee_to(fee_to: Principal) -> Result<(), TxError> {
+    _only_role(Role::FeeAdmin)?;
+    ic::get_mut::<Metadata>().fee_to = fee_to;
+    Ok(())
+}
+
+// Ownership only moves once the proposed owner accepts it, so a mistyped
+// principal can be cancelled or overridden. Holders of the owner role cannot
+// propose or cancel, only the owner itself.
+#[update(name = "proposeOwner")]
+#[candid_method(update, rename = "proposeOwner")]
+fn propose_owner(owner: Principal) -> TxReceipt {
+    let caller = ic::caller();
+    let metadata = ic::get_mut::<Metadata>();
+    if caller != metadata.owner {
+        return Err(TxError::Unauthorized);
+    }
+    metadata.pending_owner = Some(owner);
+    Ok(_record_ownership(Operation::ProposeOwner, caller, owner))
+}
+
+#[update(name = "acceptOwnership")]
+#[candid_method(update, rename = "acceptOwnership")]
+fn accept_ownership() -> TxReceipt {
+    let caller = ic::caller();
+    let metadata = ic::get_mut::<Metadata>();
+    if metadata.pending_owner != Some(caller) {
+        return Err(TxError::Unauthorized);
+    }
+    let previous = std::mem::replace(&mut metadata.owner, caller);
+    metadata.pending_owner = None;
+    Ok(_record_ownership(Operation::AcceptOwnership, previous, caller))
+}
+
+#[update(name = "cancelOwnershipTransfer")]
+#[candid_method(update, rename = "cancelOwnershipTransfer")]
+fn cancel_ownership_transfer() -> TxReceipt {
+    let caller = ic::caller();
+    let metadata = ic::get_mut::<Metadata>();
+    if caller != metadata.owner {
+        return Err(TxError::Unauthorized);
+    }
+    let pending = metadata.pending_owner.take().ok_or_else(|| TxError::Other {
+        code: ERR_NO_PENDING_OWNER,
+        message: "no pending owner".to_string(),
+    })?;
+    Ok(_record_ownership(
+        Operation::CancelOwnershipTransfer,
+        caller,
+        pending,
+    ))
+}
+
+// from the current owner to the proposed one
+fn _record_ownership(op: Operation, from: Principal, to: Principal) -> usize {
+    add_record(
+        Some(ic::caller()),
+        op,
+        from.into(),
+        to.into(),
+        Nat::from(0),
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+        None,
+        None,
+    )
+}
+
+#[update(name = "setArchiveOptions")]
+#[candid_method(update, rename = "setArchiveOptions")]
+fn set_archive_options(options: ArchiveOptions) -> Result<(), TxError> {
+    _only_role(Role::Owner)?;
+    ic::get_mut::<Archives>().options = options;
+    Ok(())
+}
+
+#[update(name = "setArchiveWasm")]
+#[candid_method(update, rename = "setArchiveWasm")]
+fn set_archive_wasm(wasm: Vec<u8>) -> Result<(), TxError> {
+    _only_role(Role::Owner)?;
+    storage::set_archive_wasm(wasm);
+    archive::mark_outdated();
+    Ok(())
+}
+
+#[query(name = "balanceOf")]
+#[candid_method(query, rename = "balanceOf")]
+fn balance_of(id: Principal) -> Nat {
+    _balance_of(&id.into())
+}
+
+// Malformed subaccounts cannot hold tokens, their balance is zero.
+#[query(name = "balanceOfAccount")]
+#[candid_method(query, rename = "balanceOfAccount")]
+fn balance_of_account(account: Account) -> Nat {
+    match Account::try_new(account.owner, account.subaccount) {
+        Ok(account) => _balance_of(&account),
+        Err(_) => Nat::from(0),
+    }
+}
+
+fn _balance_of(account: &Account) -> Nat {
+    storage::balance(account).unwrap_or_else(|| Nat::from(0))
+}
+
+// Balance minus what is still locked by a vesting schedule.
+fn _spendable_balance(account: &Account) -> Nat {
+    let balance = _balance_of(account);
+    if account.subaccount.is_some() {
+        return balance;
+    }
+    let locked = vesting::locked(&account.owner);
+    if balance > locked {
+        balance - locked
+    } else {
+        Nat::from(0)
+    }
+}
+
+#[query(name = "allowance")]
+#[candid_method(query)]
+fn allowance(owner: Principal, spender: Principal) -> Nat {
+    _allowance(&owner.into(), &spender.into()).allowance
+}
+
+// Expired allowances read as zero.
+fn _allowance(owner: &Account, spender: &Account) -> Allowance {
+    match storage::allowance(owner, spender) {
+        Some(a) if !a.is_expired(ic::time()) => a,
+        _ => Allowance {
+            allowance: Nat::from(0),
+            expires_at: None,
+        },
+    }
+}
+
+#[query(name = "getLogo")]
+#[candid_method(query, rename = "getLogo")]
+fn get_logo() -> String {
+    let metadata = ic::get::<Metadata>();
+    metadata.logo.clone()
+}
+
+#[query(name = "name")]
+#[candid_method(query)]
+fn name() -> String {
     let metadata = ic::get::<Metadata>();
     metadata.name.clone()
 }
@@ -457,112 +1838,484 @@ fn get_metadata() -> Metadata {
 #[query(name = "historySize")]
 #[candid_method(query, rename = "historySize")]
 fn history_size() -> usize {
-    let ops = ic::get::<Ops>();
-    ops.len()
+    archive::first_local_index() + storage::local_op_count()
 }
 
 #[query(name = "getTransaction")]
 #[candid_method(query, rename = "getTransaction")]
-fn get_transaction(index: usize) -> OpRecord {
-    let ops = ic::get::<Ops>();
-    ops[index].clone()
+fn get_transaction(index: usize) -> TransactionResponse {
+    if let Some(archive) = archive::archive_of(index) {
+        return TransactionResponse::Archived(archive);
+    }
+    match storage::op(index) {
+        Some(record) => TransactionResponse::Found(record),
+        None => TransactionResponse::NotFound,
+    }
+}
+
+#[query(name = "balanceOfCertified")]
+#[candid_method(query, rename = "balanceOfCertified")]
+fn balance_of_certified(account: Account) -> Result<CertifiedBalance, TxError> {
+    let account = _account(account.owner, account.subaccount)?;
+    Ok(CertifiedBalance {
+        balance: _balance_of(&account),
+        certificate: data_certificate(),
+        witness: certification::balance_witness(&account),
+    })
+}
+
+#[query(name = "getTransactionCertified")]
+#[candid_method(query, rename = "getTransactionCertified")]
+fn get_transaction_certified(index: usize) -> CertifiedTransaction {
+    CertifiedTransaction {
+        transaction: get_transaction(index),
+        certificate: data_certificate(),
+        witness: certification::transaction_witness(index),
+    }
+}
+
+fn data_certificate() -> Vec<u8> {
+    ic::data_certificate().expect("certified data is only available in query calls")
 }
 
 #[query(name = "getTransactions")]
 #[candid_method(query, rename = "getTransactions")]
-fn get_transactions(start: usize, limit: usize) -> Vec<OpRecord> {
-    let mut ret: Vec<OpRecord> = Vec::new();
-    let ops = ic::get::<Ops>();
-    let mut i = start;
-    while i < start + limit && i < ops.len() {
-        ret.push(ops[i].clone());
-        i += 1;
+fn get_transactions(start: usize, limit: usize) -> TransactionsResponse {
+    let first_index = std::cmp::max(start, archive::first_local_index());
+    let end = start.saturating_add(limit);
+    TransactionsResponse {
+        first_index,
+        transactions: storage::ops(first_index, end.saturating_sub(first_index)),
+        archived: archive::archived_ranges(start, limit),
+    }
+}
+
+#[query(name = "getBlocks")]
+#[candid_method(query, rename = "getBlocks")]
+fn get_blocks(start: usize, length: usize) -> BlocksResponse {
+    let transactions = get_transactions(start, length);
+    BlocksResponse {
+        first_index: transactions.first_index,
+        blocks: transactions.transactions.into_iter().map(blocks::encode).collect(),
+        tip_hash: blocks::tip_hash(history_size()),
+        archived: transactions.archived,
     }
-    ret
+}
+
+// Checks the local blocks `[start, start + length)`, see `blocks::verify`.
+#[query(name = "verifyChain")]
+#[candid_method(query, rename = "verifyChain")]
+fn verify_chain(start: usize, length: usize) -> Result<usize, String> {
+    blocks::verify(start, length, archive::first_local_index())
+}
+
+#[query(name = "getArchives")]
+#[candid_method(query, rename = "getArchives")]
+fn get_archives() -> Vec<ArchiveInfo> {
+    ic::get::<Archives>().archives.clone()
+}
+
+#[query(name = "getArchiveOptions")]
+#[candid_method(query, rename = "getArchiveOptions")]
+fn get_archive_options() -> ArchiveOptions {
+    ic::get::<Archives>().options.clone()
 }
 
 #[query(name = "getUserTransactionAmount")]
 #[candid_method(query, rename = "getUserTransactionAmount")]
 fn get_user_transaction_amount(a: Principal) -> usize {
-    let mut res = 0;
-    let ops = ic::get::<Ops>();
-    for i in ops.clone() {
-        if i.caller == Some(a) || i.from == a || i.to == a {
-            res += 1;
-        }
-    }
-    res
+    storage::user_op_count(&a)
 }
 
+// Records of the page that were moved to an archive are returned as runs of
+// indices in `archived`, the archives can be asked for them by index.
 #[query(name = "getUserTransactions")]
 #[candid_method(query, rename = "getUserTransactions")]
-fn get_user_transactions(a: Principal, start: usize, limit: usize) -> Vec<OpRecord> {
-    let ops = ic::get::<Ops>();
-    let mut res: Vec<OpRecord> = Vec::new();
-    let mut index: usize = 0;
-    for i in ops.clone() {
-        if i.caller == Some(a) || i.from == a || i.to == a {
-            if index >= start && index < start + limit {
-                res.push(i);
+fn get_user_transactions(a: Principal, start: usize, limit: usize) -> UserTransactionsResponse {
+    let mut transactions = Vec::new();
+    let mut archived: Vec<ArchiveInfo> = Vec::new();
+    for index in storage::user_ops(&a, start, limit) {
+        if let Some(record) = storage::op(index) {
+            transactions.push(record);
+        } else if let Some(archive) = archive::archive_of(index) {
+            match archived.last_mut() {
+                Some(last) if last.start + last.length == index && last.start >= archive.start => last.length += 1,
+                _ => archived.push(ArchiveInfo { canister_id: archive.canister_id, start: index, length: 1 }),
             }
-            index += 1;
         }
     }
-    res
+    UserTransactionsResponse { transactions, archived }
 }
 
 #[query(name = "getTokenInfo")]
 #[candid_method(query, rename = "getTokenInfo")]
 fn get_token_info() -> TokenInfo {
     let metadata = ic::get::<Metadata>().clone();
-    let ops = ic::get::<Ops>();
-    let balance = ic::get::<Balances>();
 
-    return TokenInfo {
+    TokenInfo {
         metadata: metadata.clone(),
         fee_to: metadata.fee_to,
-        history_size: ops.len(),
-        deploy_time: ops[0].timestamp,
-        holder_number: balance.len(),
+        history_size: history_size(),
+        deploy_time: ic::get::<DeployTime>().0,
+        holder_number: storage::holder_count(),
         cycles: ic::balance(),
-    };
+        paused: *ic::get::<PauseFlags>(),
+    }
 }
 
+// Walks past the first `start` holders, deep pages are cheaper through
+// getHoldersAfter.
 #[query(name = "getHolders")]
 #[candid_method(query, rename = "getHolders")]
-fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
-    let mut balance = Vec::new();
-    for (k, v) in ic::get::<Balances>().clone() {
-        balance.push((k, v.clone()));
-    }
-    balance.sort_by(|a, b| b.1.cmp(&a.1));
-    let limit: usize = if start + limit > balance.len() {
-        balance.len() - start
-    } else {
-        limit
-    };
-    balance[start..start + limit].to_vec()
+fn get_holders(start: usize, limit: usize) -> Vec<(Account, Nat)> {
+    storage::holders(start, limit)
+}
+
+// The page of holders following `after`, the last holder returned by the
+// previous page. Unlike getHolders its cost does not grow with the depth of
+// the page.
+#[query(name = "getHoldersAfter")]
+#[candid_method(query, rename = "getHoldersAfter")]
+fn get_holders_after(after: Option<(Account, Nat)>, limit: usize) -> Vec<(Account, Nat)> {
+    storage::holders_after(after, limit)
+}
+
+// Roles held by `user`, the owner holds all of them.
+#[query(name = "getRoles")]
+#[candid_method(query, rename = "getRoles")]
+fn get_roles(user: Principal) -> Vec<Role> {
+    ALL_ROLES
+        .iter()
+        .copied()
+        .filter(|role| _has_role(&user, *role))
+        .collect()
+}
+
+// Principals the role was granted to directly, the owner is listed first.
+#[query(name = "getRoleMembers")]
+#[candid_method(query, rename = "getRoleMembers")]
+fn get_role_members(role: Role) -> Vec<Principal> {
+    let owner = ic::get::<Metadata>().owner;
+    let mut members = vec![owner];
+    members.extend(storage::role_members(role).into_iter().filter(|user| *user != owner));
+    members
+}
+
+#[query(name = "getFeeSchedule")]
+#[candid_method(query, rename = "getFeeSchedule")]
+fn get_fee_schedule() -> FeeSchedule {
+    fees::schedule()
+}
+
+#[query(name = "getFeeRecipients")]
+#[candid_method(query, rename = "getFeeRecipients")]
+fn get_fee_recipients() -> Vec<FeeRecipient> {
+    fees::recipients()
+}
+
+#[query(name = "getFeeStats")]
+#[candid_method(query, rename = "getFeeStats")]
+fn get_fee_stats(start_day: u64, days: u64) -> FeeStats {
+    fees::stats(start_day, days)
+}
+
+// Every schedule vesting to `user`, a principal can hold several.
+#[query(name = "getVestingSchedule")]
+#[candid_method(query, rename = "getVestingSchedule")]
+fn get_vesting_schedule(user: Principal) -> Vec<VestingSchedule> {
+    storage::vesting_schedules(&user)
+}
+
+// Alias of getVestingSchedule.
+#[query(name = "getVestingSchedules")]
+#[candid_method(query, rename = "getVestingSchedules")]
+fn get_vesting_schedules(user: Principal) -> Vec<VestingSchedule> {
+    get_vesting_schedule(user)
+}
+
+// Open escrows `user` sends, receives or arbitrates.
+#[query(name = "getEscrows")]
+#[candid_method(query, rename = "getEscrows")]
+fn get_escrows(user: Principal) -> Vec<Escrow> {
+    storage::escrows_of(&user)
+}
+
+#[query(name = "getStake")]
+#[candid_method(query, rename = "getStake")]
+fn get_stake(user: Principal) -> StakeInfo {
+    let pool = storage::staking_pool();
+    let stake = storage::stake(&user).unwrap_or_default();
+    StakeInfo {
+        pending_rewards: stake.pending_rewards(&pool.reward_per_token_at(ic::time())),
+        staked: stake.staked,
+        unbonding: stake.unbonding,
+    }
+}
+
+#[query(name = "getStakingPool")]
+#[candid_method(query, rename = "getStakingPool")]
+fn get_staking_pool() -> StakingPool {
+    storage::staking_pool()
+}
+
+#[query(name = "getMaxSupply")]
+#[candid_method(query, rename = "getMaxSupply")]
+fn get_max_supply() -> Option<Nat> {
+    ic::get::<Metadata>().max_supply.clone()
+}
+
+#[query(name = "getMinterQuota")]
+#[candid_method(query, rename = "getMinterQuota")]
+fn get_minter_quota(minter: Principal) -> Option<MinterQuota> {
+    storage::minter_quota(&minter).map(MinterQuota::today)
+}
+
+#[query(name = "isFrozen")]
+#[candid_method(query, rename = "isFrozen")]
+fn is_frozen(user: Principal) -> bool {
+    storage::is_frozen(&user)
+}
+
+#[query(name = "getFrozen")]
+#[candid_method(query, rename = "getFrozen")]
+fn get_frozen() -> Vec<Principal> {
+    storage::frozen()
 }
 
 #[query(name = "getAllowanceSize")]
 #[candid_method(query, rename = "getAllowanceSize")]
 fn get_allowance_size() -> usize {
-    let mut size = 0;
-    let allowances = ic::get::<Allowances>();
-    for (_, v) in allowances.iter() {
-        size += v.len();
-    }
-    size
+    storage::allowance_count()
 }
 
 #[query(name = "getUserApprovals")]
 #[candid_method(query, rename = "getUserApprovals")]
-fn get_user_approvals(who: Principal) -> Vec<(Principal, Nat)> {
-    let allowances = ic::get::<Allowances>();
-    match allowances.get(&who) {
-        Some(allow) => return Vec::from_iter(allow.clone().into_iter()),
-        None => return Vec::new(),
+fn get_user_approvals(who: Principal) -> Vec<(Account, Nat)> {
+    storage::allowances_of(&who.into())
+        .into_iter()
+        .filter(|(_, a)| !a.is_expired(ic::time()))
+        .map(|(spender, a)| (spender, a.allowance))
+        .collect()
+}
+
+// ICRC-1 interface, served from the same balances and history as DIP20.
+
+// The ICRC calls may state the fee they expect to pay.
+fn _check_fee(fee: &Option<Nat>, expected_fee: &Nat) -> Result<(), TxError> {
+    match fee {
+        Some(fee) if fee != expected_fee => Err(TxError::BadFee {
+            expected_fee: expected_fee.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[query(name = "icrc1_name")]
+#[candid_method(query)]
+fn icrc1_name() -> String {
+    name()
+}
+
+#[query(name = "icrc1_symbol")]
+#[candid_method(query)]
+fn icrc1_symbol() -> String {
+    symbol()
+}
+
+#[query(name = "icrc1_decimals")]
+#[candid_method(query)]
+fn icrc1_decimals() -> u8 {
+    decimals()
+}
+
+#[query(name = "icrc1_fee")]
+#[candid_method(query)]
+fn icrc1_fee() -> Nat {
+    fees::icrc1_fee()
+}
+
+#[query(name = "icrc1_metadata")]
+#[candid_method(query)]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    let metadata = ic::get::<Metadata>();
+    vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(metadata.name.clone())),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(metadata.symbol.clone())),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(metadata.decimals))),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(fees::icrc1_fee())),
+        ("icrc1:logo".to_string(), MetadataValue::Text(metadata.logo.clone())),
+    ]
+}
+
+#[query(name = "icrc1_total_supply")]
+#[candid_method(query)]
+fn icrc1_total_supply() -> Nat {
+    total_supply()
+}
+
+// Tokens sent to the minting account through icrc1_transfer are burned.
+#[query(name = "icrc1_minting_account")]
+#[candid_method(query)]
+fn icrc1_minting_account() -> Option<Account> {
+    Some(minting_account())
+}
+
+#[query(name = "icrc1_balance_of")]
+#[candid_method(query)]
+fn icrc1_balance_of(account: Account) -> Nat {
+    balance_of_account(account)
+}
+
+#[update(name = "icrc1_transfer")]
+#[candid_method(update)]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let from = _account(ic::caller(), arg.from_subaccount)?;
+    let to = _account(arg.to.owner, arg.to.subaccount)?;
+    let txid = if to == minting_account() {
+        // burns are free, but must be worth at least a transfer fee
+        _check_fee(&arg.fee, &Nat::from(0))?;
+        let min_burn_amount = ic::get::<Metadata>().fee.clone();
+        if arg.amount < min_burn_amount {
+            return Err(TransferError::BadBurn { min_burn_amount });
+        }
+        _burn(from.clone(), from, arg.amount, arg.memo, arg.created_at_time)?
+    } else {
+        _check_fee(&arg.fee, &fees::fee(Operation::Transfer, &from.owner, &arg.amount))?;
+        _send(from, to, arg.amount, arg.memo, arg.created_at_time)?
+    };
+    Ok(Nat::from(txid))
+}
+
+#[query(name = "icrc1_supported_standards")]
+#[candid_method(query)]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+// ICRC-2 interface, sharing allowances with DIP20. Unlike `approve`, the
+// allowance is set to exactly `amount` and the fee of each transfer_from is
+// taken out of it.
+
+#[update(name = "icrc2_approve")]
+#[candid_method(update)]
+fn icrc2_approve(arg: ApproveArgs) -> Result<Nat, ApproveError> {
+    let owner = _account(ic::caller(), arg.from_subaccount)?;
+    let spender = _account(arg.spender.owner, arg.spender.subaccount)?;
+    if spender == owner {
+        return Err(ApproveError::GenericError {
+            error_code: Nat::from(ERR_UNSUPPORTED),
+            message: "cannot approve yourself".to_string(),
+        });
+    }
+    _check_paused(Operation::Approve)?;
+    _check_frozen(&[owner.owner, spender.owner])?;
+    let metadata = ic::get::<Metadata>();
+    let fee = fees::fee(Operation::Approve, &owner.owner, &arg.amount);
+    _check_fee(&arg.fee, &fee)?;
+    let dedup = dedup::check(dedup::Transaction {
+        caller: owner.owner,
+        op: Operation::Approve,
+        from: &owner,
+        to: &spender,
+        amount: &arg.amount,
+        memo: &arg.memo,
+        created_at_time: arg.created_at_time,
+    })?;
+    let now = ic::time();
+    if let Some(expires_at) = arg.expires_at {
+        if expires_at <= now {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+    }
+    if let Some(expected) = arg.expected_allowance {
+        let current = _allowance(&owner, &spender).allowance;
+        if current != expected {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: current,
+            });
+        }
+    }
+    let balance = _spendable_balance(&owner);
+    if balance < fee {
+        return Err(ApproveError::InsufficientFunds { balance });
+    }
+    _charge_fee(Operation::Approve, &owner, metadata.fee_to, fee.clone());
+    storage::set_allowance(
+        owner.clone(),
+        spender.clone(),
+        Allowance {
+            allowance: arg.amount.clone(),
+            expires_at: arg.expires_at,
+        },
+    );
+    let txid = add_record(
+        None,
+        Operation::Approve,
+        owner,
+        spender,
+        arg.amount,
+        fee,
+        now,
+        TransactionStatus::Succeeded,
+        arg.memo,
+        arg.created_at_time,
+    );
+    dedup::remember(dedup, txid);
+    Ok(Nat::from(txid))
+}
+
+#[query(name = "icrc2_allowance")]
+#[candid_method(query)]
+fn icrc2_allowance(arg: AllowanceArgs) -> Allowance {
+    let account = Account::try_new(arg.account.owner, arg.account.subaccount);
+    let spender = Account::try_new(arg.spender.owner, arg.spender.subaccount);
+    match (account, spender) {
+        (Ok(account), Ok(spender)) => _allowance(&account, &spender),
+        // malformed subaccounts cannot be approved
+        _ => Allowance {
+            allowance: Nat::from(0),
+            expires_at: None,
+        },
+    }
+}
+
+#[update(name = "icrc2_transfer_from")]
+#[candid_method(update)]
+fn icrc2_transfer_from(arg: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let spender = _account(ic::caller(), arg.spender_subaccount)?;
+    let from = _account(arg.from.owner, arg.from.subaccount)?;
+    let to = _account(arg.to.owner, arg.to.subaccount)?;
+    if to == minting_account() {
+        return Err(TransferFromError::GenericError {
+            error_code: Nat::from(ERR_UNSUPPORTED),
+            message: "burning through transfer_from is not supported".to_string(),
+        });
     }
+    _check_fee(&arg.fee, &fees::fee(Operation::TransferFrom, &spender.owner, &arg.amount))?;
+    _transfer_from(
+        spender.clone(),
+        from.clone(),
+        to,
+        arg.amount,
+        arg.memo,
+        arg.created_at_time,
+    )
+    .map(Nat::from)
+    .map_err(|err| match err {
+        TxError::InsufficientAllowance => TransferFromError::InsufficientAllowance {
+            allowance: _allowance(&from, &spender).allowance,
+        },
+        err => err.into(),
+    })
 }
 
 #[cfg(any(target_arch = "wasm32", test))]
@@ -574,55 +2327,133 @@ fn main() {
     std::print!("{}", __export_service());
 }
 
-// TODO: fix upgrade functions
 #[pre_upgrade]
 fn pre_upgrade() {
     let metadata = ic::get::<Metadata>().clone();
-    let mut balance = Vec::new();
-    // let mut allow: Vec<(Principal, Vec<(Principal, Nat)>)> = Vec::new();
-    let mut allow = Vec::new();
-    for (k, v) in ic::get::<Balances>().clone() {
-        balance.push((k, v));
-    }
-    for (k, v) in ic::get::<Allowances>().iter() {
-        let mut item = Vec::new();
-        for (a, b) in v.clone() {
-            item.push((a, b));
-        }
-        allow.push((*k, item));
-    }
-    let up = UpgradePayload {
+    let archives = ic::get::<Archives>();
+    let up = UpgradePayload::V5(UpgradePayloadV5 {
         metadata,
-        balance,
-        allow,
-    };
-    ic::stable_store((up,)).unwrap();
+        deploy_time: ic::get::<DeployTime>().0,
+        archive_options: archives.options.clone(),
+        archives: archives.archives.clone(),
+        paused: *ic::get::<PauseFlags>(),
+        uninstalled_archive: archives.uninstalled,
+        user_index_built: true,
+    });
+    storage::set_upgrade_payload(candid::encode_one(up).unwrap());
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    // There can only be one value in stable memory, currently. otherwise, lifetime error.
-    // https://docs.rs/ic-cdk/0.3.0/ic_cdk/storage/fn.stable_restore.html
-    let (down,): (UpgradePayload,) = ic::stable_restore().unwrap();
-    let metadata = ic::get_mut::<Metadata>();
-    *metadata = down.metadata;
-    for (k, v) in down.balance {
-        ic::get_mut::<Balances>().insert(k, v);
-    }
-    for (k, v) in down.allow {
-        let mut inner = HashMap::new();
-        for (a, b) in v {
-            inner.insert(a, b);
+    let payload = if storage::is_managed() {
+        candid::decode_one(&storage::upgrade_payload()).unwrap()
+    } else {
+        // Stable memory still holds the single `stable_store` value written by a
+        // canister that kept its state on the heap, import it once.
+        match ic::stable_restore::<(UpgradePayload,)>() {
+            Ok((payload,)) => payload,
+            Err(_) => {
+                let (down,): (UpgradePayloadV0,) = ic::stable_restore().unwrap();
+                UpgradePayload::V1(UpgradePayloadV1 {
+                    metadata: down.metadata,
+                    balance: down.balance,
+                    allow: down.allow,
+                    ops: Vec::new(),
+                })
+            }
+        }
+    };
+    let down = match payload {
+        UpgradePayload::V1(down) => {
+            for (k, v) in down.balance {
+                storage::set_balance(k.into(), v);
+            }
+            for (k, v) in down.allow {
+                for (a, b) in v {
+                    storage::set_allowance(
+                        k.into(),
+                        a.into(),
+                        Allowance {
+                            allowance: b,
+                            expires_at: None,
+                        },
+                    );
+                }
+            }
+            for op in down.ops {
+                storage::append_op(&op.into());
+            }
+            build_stable_indexes();
+            UpgradePayloadV5 {
+                metadata: down.metadata,
+                deploy_time: storage::op(0).map_or(0, |r| r.timestamp),
+                archive_options: ArchiveOptions::default(),
+                archives: Vec::new(),
+                paused: PauseFlags::default(),
+                uninstalled_archive: None,
+                user_index_built: false,
+            }
+        }
+        UpgradePayload::V3(down) => {
+            build_stable_indexes();
+            UpgradePayloadV5 {
+                metadata: down.metadata,
+                deploy_time: down.deploy_time,
+                archive_options: down.archive_options,
+                archives: down.archives,
+                paused: PauseFlags::default(),
+                uninstalled_archive: None,
+                user_index_built: false,
+            }
         }
-        ic::get_mut::<Allowances>().insert(k, inner);
+        UpgradePayload::V5(down) => down,
+    };
+    if !down.user_index_built {
+        // records already archived cannot be read back here and stay out of
+        // the user index
+        index_local_records();
+    }
+    certification::certify();
+    *ic::get_mut::<Metadata>() = down.metadata;
+    *ic::get_mut::<DeployTime>() = DeployTime(down.deploy_time);
+    let archives = ic::get_mut::<Archives>();
+    archives.options = down.archive_options;
+    archives.archives = down.archives;
+    archives.uninstalled = down.uninstalled_archive;
+    archive::mark_outdated();
+    *ic::get_mut::<PauseFlags>() = down.paused;
+}
+
+// The holder order, the certified tries and the block hashes, which canisters
+// from before V5 did not keep in stable memory. Built once, later upgrades
+// find them there.
+fn build_stable_indexes() {
+    storage::index_holders();
+    certification::rebuild();
+    blocks::index_local_records();
+}
+
+fn index_local_records() {
+    for record in storage::ops(0, usize::MAX) {
+        index_record(&record);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ic_kit::{mock_principals::{alice, bob, john}, MockContext};
-    use assert_panic::assert_panic;
+    use token::{block_hash, Block};
+    use fees::FeeModel;
+    use ic_kit::{mock_principals::{alice, bob, john}, MockContext, Method, RawHandler, RejectionCode};
+    use ic_kit::interfaces::management::{InstallCodeArgument, InstallMode, WithCanisterId};
+
+    fn found_transaction(index: usize) -> OpRecord {
+      match get_transaction(index) {
+        TransactionResponse::Found(record) => record,
+        TransactionResponse::Archived(archive) => panic!("transaction {} was archived to {:?}", index, archive),
+        TransactionResponse::NotFound => panic!("transaction {} was not found", index),
+      }
+    }
 
     fn initialize_tests() {
       init(
@@ -630,15 +2461,15 @@ mod tests {
         String::from("token"),
         String::from("TOKEN"),
         2,
-        1_000,
+        Nat::from(1_000),
         alice(),
-        1,
+        Nat::from(1),
       );
     }
 
     #[test]
     fn functionality_test() {
-      MockContext::new()
+      let ctx = MockContext::new()
       .with_balance(100_000)
       .with_caller(alice())
       .inject();
@@ -654,7 +2485,8 @@ mod tests {
       assert_eq!(get_logo(), String::from("logo"), "getLogo did not return the correct value");
       assert_eq!(decimals(), 2, "decimals did not return the correct value");
       assert_eq!(get_holders(0, 10).len(), 1, "get_holders returned the correct amount of holders after initialization");
-      assert_eq!(get_transaction(0).op, Operation::Mint, "get_transaction returnded a Mint operation");
+      assert_eq!(found_transaction(0).op, Operation::Mint, "get_transaction returnded a Mint operation");
+      assert!(matches!(get_transaction(1), TransactionResponse::NotFound), "get_transaction found a record past the history");
 
       let token_info = get_token_info();
       assert_eq!(token_info.fee_to, Principal::anonymous(), "tokenInfo.fee_to did not return the correct value");
@@ -674,71 +2506,1302 @@ mod tests {
       assert_eq!(metadata.fee_to, Principal::anonymous(), "metadata.fee_to did not return the correct value");
 
       // set fee test
-      set_fee(2);
-      assert_eq!(2, get_metadata().fee ,"Failed to update the fee_to");
+      set_fee(Nat::from(2)).unwrap();
+      assert_eq!(Nat::from(2), get_metadata().fee ,"Failed to update the fee_to");
 
       // set fee_to test
-      set_fee_to(john());
+      set_fee_to(john()).unwrap();
       assert_eq!(john(), get_metadata().fee_to, "Failed to set fee");
-      set_fee_to(Principal::anonymous());
+      set_fee_to(Principal::anonymous()).unwrap();
 
       // set logo
-      set_logo(String::from("new_logo"));
+      set_logo(String::from("new_logo")).unwrap();
       assert_eq!("new_logo", get_logo());
 
       // test transfers
       let transfer_alice_balance_expected = balance_of(alice()) - 10 - get_metadata().fee;
       let transfer_bob_balance_expected = balance_of(bob()) + 10;
       let transfer_john_balance_expected = balance_of(john());
-      let transfer_transaction_amount_expected = get_transactions(0, 10).len() + 1;
+      let transfer_transaction_amount_expected = get_transactions(0, 10).transactions.len() + 1;
       let transfer_user_transaction_amount_expected = get_user_transaction_amount(alice()) + 1;
-      transfer(bob(), 10).map_err(|err| println!("{:?}", err)).ok();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
 
       assert_eq!(balance_of(alice()), transfer_alice_balance_expected, "Transfer did not transfer the expected amount to Alice");
       assert_eq!(balance_of(bob()), transfer_bob_balance_expected, "Transfer did not transfer the expected amount to Bob");
       assert_eq!(balance_of(john()), transfer_john_balance_expected, "Transfer did not transfer the expected amount to John");
-      assert_eq!(get_transactions(0, 10).len(), transfer_transaction_amount_expected, "transfer operation did not produce a transaction");
+      assert_eq!(get_transactions(0, 10).transactions.len(), transfer_transaction_amount_expected, "transfer operation did not produce a transaction");
       assert_eq!(get_user_transaction_amount(alice()), transfer_user_transaction_amount_expected, "get_user_transaction_amount returned the wrong value after a transfer");
-      assert_eq!(get_user_transactions(alice(), 0, 10).len(), transfer_user_transaction_amount_expected, "get_user_transactions returned the wrong value after a transfer");
+      assert_eq!(get_user_transactions(alice(), 0, 10).transactions.len(), transfer_user_transaction_amount_expected, "get_user_transactions returned the wrong value after a transfer");
       assert_eq!(get_holders(0, 10).len(), 3, "get_holders returned the correct amount of holders after transfer");
-      assert_eq!(get_transaction(1).op, Operation::Transfer, "get_transaction returnded a Transfer operation");
+      assert_eq!(found_transaction(1).op, Operation::Transfer, "get_transaction returnded a Transfer operation");
 
       // test allowances
-      approve(bob(), 100).map_err(|err| println!("{:?}", err)).ok();
-      assert_eq!(allowance(alice(), bob()), 100 + get_metadata().fee, "Approve did not give the correct allowance");
+      approve(bob(), Nat::from(100)).map_err(|err| println!("{:?}", err)).ok();
+      assert_eq!(allowance(alice(), bob()), Nat::from(100) + get_metadata().fee, "Approve did not give the correct allowance");
       assert_eq!(get_allowance_size(), 1, "getAllowanceSize returns the correct value");
       assert_eq!(get_user_approvals(alice()).len(), 1, "getUserApprovals not returning the correct value");
 
       // test transfer_from
       // inserting an allowance of Alice for Bob's balance to test transfer_from
-      let allowances = ic::get_mut::<Allowances>();
-      let mut inner = HashMap::new();
-      inner.insert(alice(), 5 + get_metadata().fee);
-      allowances.insert(bob(), inner);
+      storage::set_allowance(bob().into(), alice().into(), Allowance { allowance: Nat::from(5) + get_metadata().fee, expires_at: None });
 
       let transfer_from_alice_balance_expected = balance_of(alice());
       let transfer_from_bob_balance_expected = balance_of(bob()) - 5 - get_metadata().fee;
       let transfer_from_john_balance_expected = balance_of(john()) + 5;
-      let transfer_from_transaction_amount_expected = get_transactions(0, 10).len() + 1;
+      let transfer_from_transaction_amount_expected = get_transactions(0, 10).transactions.len() + 1;
 
-      transfer_from(bob(), john(), 5).map_err(|err| println!("{:?}", err)).ok();
+      transfer_from(bob(), john(), Nat::from(5), None, None).map_err(|err| println!("{:?}", err)).ok();
 
       assert_eq!(balance_of(alice()), transfer_from_alice_balance_expected, "transfer_from transferred the correct value for alice");
       assert_eq!(balance_of(bob()), transfer_from_bob_balance_expected, "transfer_from transferred the correct value for bob");
       assert_eq!(balance_of(john()), transfer_from_john_balance_expected, "transfer_from transferred the correct value for john");
       assert_eq!(allowance(bob(), alice()), 0, "allowance has not been spent");
-      assert_eq!(get_transactions(0, 10).len(), transfer_from_transaction_amount_expected, "transfer_from operation did not produce a transaction");
+      assert_eq!(get_transactions(0, 10).transactions.len(), transfer_from_transaction_amount_expected, "transfer_from operation did not produce a transaction");
 
       // Transferring more than the balance
-      assert_eq!(transfer(alice(), 1_000_000), Err(TxError::InsufficientBalance) , "alice was able to transfer more than is allowed");
+      assert_eq!(transfer(alice(), Nat::from(1_000_000), None, None), Err(TxError::InsufficientBalance) , "alice was able to transfer more than is allowed");
       // Transferring more than the balance
-      assert_eq!(transfer_from(bob(), john(), 1_000_000), Err(TxError::InsufficientAllowance) , "alice was able to transfer more than is allowed");
+      assert_eq!(transfer_from(bob(), john(), Nat::from(1_000_000), None, None), Err(TxError::InsufficientAllowance) , "alice was able to transfer more than is allowed");
 
       //set owner test
-      set_owner(bob());
+      propose_owner(bob()).unwrap();
+      assert_eq!(alice(), owner(), "ownership moved before it was accepted");
+      ctx.update_caller(bob());
+      accept_ownership().unwrap();
       assert_eq!(bob(), owner(), "Failed to set new owner");
     }
 
+    #[test]
+    fn upgrade_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      approve(bob(), Nat::from(100)).map_err(|err| println!("{:?}", err)).ok();
+      let deploy_time = get_token_info().deploy_time;
+
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+
+      assert_eq!(history_size(), 3, "history was not restored after upgrade");
+      assert_eq!(found_transaction(0).op, Operation::Mint, "genesis mint was not restored after upgrade");
+      assert_eq!(found_transaction(1).op, Operation::Transfer, "transfer record was not restored after upgrade");
+      assert_eq!(found_transaction(2).op, Operation::Approve, "approve record was not restored after upgrade");
+      assert_eq!(get_token_info().deploy_time, deploy_time, "deploy_time changed after upgrade");
+      assert_eq!(balance_of(bob()), 10, "balances were not restored after upgrade");
+      assert_eq!(allowance(alice(), bob()), 101, "allowances were not restored after upgrade");
+      assert_eq!(get_metadata().symbol, String::from("TOKEN"), "metadata was not restored after upgrade");
+    }
+
+    #[test]
+    fn upgrade_from_unversioned_payload_test() {
+      MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      ic::stable_store((UpgradePayloadV0 {
+        metadata: Metadata {
+          symbol: String::from("OLD"),
+          ..Metadata::default()
+        },
+        balance: vec![(alice(), Nat::from(42))],
+        allow: vec![(alice(), vec![(bob(), Nat::from(7))])],
+      },)).unwrap();
+
+      post_upgrade();
+
+      assert_eq!(symbol(), String::from("OLD"), "metadata was not decoded from the legacy payload");
+      assert_eq!(balance_of(alice()), 42, "balances were not decoded from the legacy payload");
+      assert_eq!(allowance(alice(), bob()), 7, "allowances were not decoded from the legacy payload");
+      assert_eq!(history_size(), 0, "legacy payload should restore an empty history");
+      assert_eq!(get_token_info().deploy_time, 0, "getTokenInfo should not trap on an empty history");
+    }
+
+    #[test]
+    fn upgrade_from_heap_payload_test() {
+      MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      let record = |index: usize, op: Operation| OpRecordV0 {
+        caller: Some(alice()),
+        op,
+        index,
+        from: alice(),
+        to: bob(),
+        amount: Nat::from(10),
+        fee: Nat::from(0),
+        timestamp: 1,
+        status: TransactionStatus::Succeeded,
+      };
+      ic::stable_store((UpgradePayload::V1(UpgradePayloadV1 {
+        metadata: Metadata::default(),
+        balance: vec![(alice(), Nat::from(90)), (bob(), Nat::from(10))],
+        allow: vec![(alice(), vec![(bob(), Nat::from(7)), (john(), Nat::from(3))])],
+        ops: vec![record(0, Operation::Mint), record(1, Operation::Transfer)],
+      }),)).unwrap();
+
+      post_upgrade();
+
+      assert_eq!(balance_of(alice()), 90, "balances were not imported from the heap payload");
+      assert_eq!(get_holders(0, 10).len(), 2, "holders were not imported from the heap payload");
+      assert_eq!(get_user_approvals(alice()).len(), 2, "allowances were not imported from the heap payload");
+      assert_eq!(history_size(), 2, "history was not imported from the heap payload");
+      assert_eq!(found_transaction(1).op, Operation::Transfer, "history order was not kept when importing the heap payload");
+      assert_eq!(found_transaction(1).index, 1, "history indices were not kept when importing the heap payload");
+      assert_eq!(found_transaction(1).from, Account::from(alice()), "legacy records were not moved to the default subaccount");
+      assert_eq!(get_user_transaction_amount(bob()), 2, "the user index was not built from the heap payload");
+    }
+
+    #[test]
+    fn user_history_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      for i in 0..5 {
+        let to = if i % 2 == 0 { bob() } else { john() };
+        transfer(to, Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      }
+      ctx.update_caller(bob());
+      transfer(john(), Nat::from(1), None, None).map_err(|err| println!("{:?}", err)).ok();
+
+      assert_eq!(get_user_transaction_amount(alice()), 6, "getUserTransactionAmount did not count the genesis mint and transfers");
+      assert_eq!(get_user_transaction_amount(bob()), 4, "getUserTransactionAmount did not count both sides of a transfer");
+      assert_eq!(get_user_transaction_amount(john()), 3, "getUserTransactionAmount did not count received transfers");
+      let page: Vec<usize> = get_user_transactions(bob(), 1, 2).transactions.iter().map(|r| r.index).collect();
+      assert_eq!(page, vec![3, 5], "getUserTransactions did not return the requested page");
+      assert_eq!(get_user_transactions(john(), 2, 10).transactions.len(), 1, "getUserTransactions did not clip the page to the history");
+      assert!(get_user_transactions(john(), 10, 10).transactions.is_empty(), "getUserTransactions returned records past the history");
+    }
+
+    #[test]
+    fn upgrade_from_v3_payload_test() {
+      MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      // records written before the user index existed
+      for index in 0..3 {
+        storage::append_op(&OpRecord {
+          caller: None,
+          op: Operation::Transfer,
+          index,
+          from: alice().into(),
+          to: bob().into(),
+          amount: Nat::from(10),
+          fee: Nat::from(0),
+          timestamp: 1,
+          status: TransactionStatus::Succeeded,
+          memo: None,
+          created_at_time: None,
+        });
+      }
+      storage::set_upgrade_payload(candid::encode_one(UpgradePayload::V3(UpgradePayloadV3 {
+        metadata: Metadata::default(),
+        deploy_time: 1,
+        archive_options: ArchiveOptions::default(),
+        archives: Vec::new(),
+      })).unwrap());
+
+      post_upgrade();
+      assert_eq!(get_user_transaction_amount(bob()), 3, "the user index was not built when upgrading from V3");
+      assert_eq!(get_user_transactions(alice(), 0, 10).transactions.len(), 3, "getUserTransactions did not read the rebuilt index");
+      pre_upgrade();
+      post_upgrade();
+      assert_eq!(get_user_transaction_amount(bob()), 3, "the user index was rebuilt on a later upgrade");
+    }
+
+    #[test]
+    fn holders_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      transfer(bob(), Nat::from(100), None, None).map_err(|err| println!("{:?}", err)).ok();
+      transfer(john(), Nat::from(300), None, None).map_err(|err| println!("{:?}", err)).ok();
+
+      let holders: Vec<Principal> = get_holders(0, 10).iter().map(|(a, _)| a.owner).collect();
+      assert_eq!(holders, vec![alice(), john(), bob(), Principal::anonymous()], "getHolders did not order holders by balance");
+      assert_eq!(get_holders(1, 2), vec![(Account::from(john()), Nat::from(300)), (Account::from(bob()), Nat::from(100))], "getHolders did not return the requested page");
+      assert!(get_holders(10, 5).is_empty(), "getHolders did not return an empty page past the end");
+      assert_eq!(get_holders(3, 5).len(), 1, "getHolders did not clip the page to the holders");
+      let first = get_holders_after(None, 2);
+      assert_eq!(first, get_holders(0, 2), "getHoldersAfter did not start from the largest holder");
+      assert_eq!(get_holders_after(first.last().cloned(), 10), get_holders(2, 10), "getHoldersAfter did not continue after the cursor");
+      assert!(get_holders_after(get_holders(3, 1).pop(), 10).is_empty(), "getHoldersAfter did not return an empty page after the last holder");
+
+      // holders move when their balance changes and leave at zero
+      ctx.update_caller(bob());
+      transfer(john(), Nat::from(99), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.update_caller(alice());
+      mint(bob(), Nat::from(1_000)).map_err(|err| println!("{:?}", err)).ok();
+      let holders: Vec<Principal> = get_holders(0, 10).iter().map(|(a, _)| a.owner).collect();
+      assert_eq!(holders, vec![bob(), alice(), john(), Principal::anonymous()], "getHolders did not follow balance changes");
+      assert_eq!(get_holders(0, 10).len(), get_token_info().holder_number, "getHolders and holder_number disagree");
+    }
+
+    // Root hash of a CBOR encoded witness, along with the leaves it reveals.
+    fn reconstruct(tree: &serde_cbor::Value, leaves: &mut Vec<Vec<u8>>) -> ic_certified_map::Hash {
+      use ic_certified_map::{fork_hash, labeled_hash, leaf_hash};
+      use serde_cbor::Value;
+      let bytes = |v: &Value| match v {
+        Value::Bytes(b) => b.clone(),
+        v => panic!("expected bytes in witness, found {:?}", v),
+      };
+      match tree {
+        Value::Tag(_, tree) => reconstruct(tree, leaves),
+        Value::Array(node) => match &node[0] {
+          Value::Integer(0) => ic_certified_map::HashTree::Empty.reconstruct(),
+          Value::Integer(1) => fork_hash(&reconstruct(&node[1], leaves), &reconstruct(&node[2], leaves)),
+          Value::Integer(2) => labeled_hash(&bytes(&node[1]), &reconstruct(&node[2], leaves)),
+          Value::Integer(3) => {
+            leaves.push(bytes(&node[1]));
+            leaf_hash(&bytes(&node[1]))
+          }
+          Value::Integer(4) => {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&bytes(&node[1]));
+            hash
+          }
+          tag => panic!("unknown witness node {:?}", tag),
+        },
+        v => panic!("malformed witness {:?}", v),
+      }
+    }
+
+    #[test]
+    fn certification_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      let root = certification::root_hash().to_vec();
+      assert_eq!(ctx.get_certified_data(), Some(root.clone()), "the root hash was not certified after an update");
+
+      let certified = balance_of_certified(Account::from(bob())).unwrap();
+      assert_eq!(certified.balance, 10, "balanceOfCertified did not return the balance");
+      assert_eq!(certified.certificate, MockContext::sign(&root), "balanceOfCertified did not return the data certificate");
+      let mut leaves = Vec::new();
+      let witness: serde_cbor::Value = serde_cbor::from_slice(&certified.witness).unwrap();
+      assert_eq!(reconstruct(&witness, &mut leaves).to_vec(), root, "the balance witness does not match the certified root");
+      let mut ten = Vec::new();
+      Nat::from(10).encode(&mut ten).unwrap();
+      assert_eq!(leaves, vec![ten], "the balance witness does not reveal the balance");
+
+      let mut leaves = Vec::new();
+      let witness: serde_cbor::Value = serde_cbor::from_slice(&balance_of_certified(Account::from(john())).unwrap().witness).unwrap();
+      assert_eq!(reconstruct(&witness, &mut leaves).to_vec(), root, "the absence witness does not match the certified root");
+      assert!(leaves.is_empty(), "the witness of an empty account revealed a balance");
+
+      let certified = get_transaction_certified(1);
+      let mut leaves = Vec::new();
+      let witness: serde_cbor::Value = serde_cbor::from_slice(&certified.witness).unwrap();
+      assert_eq!(reconstruct(&witness, &mut leaves).to_vec(), root, "the transaction witness does not match the certified root");
+      match certified.transaction {
+        TransactionResponse::Found(record) => assert_eq!(leaves, vec![certification::record_hash(&record).to_vec()], "the transaction witness does not reveal the record hash"),
+        response => panic!("getTransactionCertified returned {:?}", response),
+      }
+
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+      assert_eq!(certification::root_hash().to_vec(), root, "the certified tree changed across upgrade");
+      assert_eq!(ctx.get_certified_data(), Some(root), "the root hash was not certified after upgrade");
+    }
+
+    #[test]
+    fn certification_rebuild_test() {
+      MockContext::new().with_caller(alice()).inject();
+      initialize_tests();
+
+      // holders written before the balances were certified
+      let holders: Vec<Account> = (0..5_000u32).map(|i| Account::from(Principal::from_slice(&[&i.to_be_bytes()[..], &[1]].concat()))).collect();
+      for (i, account) in holders.iter().enumerate() {
+        storage::set_balance(account.clone(), Nat::from(i + 1));
+      }
+      let started = std::time::Instant::now();
+      certification::rebuild();
+      let rebuilt = started.elapsed();
+      let root = certification::root_hash();
+
+      let started = std::time::Instant::now();
+      for (i, account) in holders.iter().enumerate() {
+        certification::set_balance(account, &Nat::from(i + 1));
+      }
+      let updated = started.elapsed();
+      assert_eq!(certification::root_hash(), root, "the rebuilt tries differ from the ones built by updates");
+      assert!(rebuilt * 2 < updated, "rebuilding {} holders took {:?}, updating them one by one {:?}", holders.len(), rebuilt, updated);
+    }
+
+    #[test]
+    fn archive_test() {
+      let archive_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("create_canister").response(WithCanisterId { canister_id: archive_id }))
+      .with_handler(Method::new().name("install_code"))
+      .with_handler(Method::new().name("append"))
+      .inject();
+
+      initialize_tests();
+      set_archive_options(ArchiveOptions {
+        trigger_threshold: 4,
+        num_records_to_archive: 2,
+        max_records_per_archive: 3,
+        cycles_for_archive_creation: 1_000_000,
+      }).unwrap();
+      for _ in 0..3 {
+        transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      }
+      assert!(get_archives().is_empty(), "records were archived before an archive wasm was uploaded");
+
+      set_archive_wasm(vec![0, 97, 115, 109]).unwrap();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert_eq!(get_archives(), vec![ArchiveInfo { canister_id: archive_id, start: 0, length: 2 }], "the oldest records were not archived");
+      assert_eq!(history_size(), 5, "archiving changed the history size");
+      assert_eq!(storage::local_op_count(), 3, "archived records were not removed locally");
+      match get_transaction(1) {
+        TransactionResponse::Archived(archive) => assert_eq!(archive.canister_id, archive_id, "get_transaction pointed at the wrong archive"),
+        response => panic!("get_transaction returned {:?} for an archived record", response),
+      }
+      assert_eq!(found_transaction(2).index, 2, "get_transaction did not return a local record");
+
+      // the first archive only has room for one more record, the next round spawns a second one
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert_eq!(get_archives().len(), 2, "no second archive was spawned once the first was full");
+      assert_eq!(get_archives()[0].length, 3, "the first archive was not filled up");
+      assert_eq!(get_archives()[1].start, 3, "the second archive does not continue the first one");
+
+      let page = get_transactions(1, 10);
+      assert_eq!(page.first_index, 5, "get_transactions did not start at the first local record");
+      assert_eq!(page.transactions.len(), 2, "get_transactions did not return the local records");
+      assert_eq!(page.archived.len(), 2, "get_transactions did not point at both archives");
+      assert_eq!(page.archived[0].start, 1, "get_transactions did not clip the archived range to the request");
+      assert_eq!(page.archived[0].length, 2, "get_transactions did not clip the archived range to the request");
+      let page = get_user_transactions(bob(), 0, 10);
+      assert_eq!(page.transactions.iter().map(|r| r.index).collect::<Vec<_>>(), vec![5, 6], "get_user_transactions did not return the local records");
+      assert_eq!(page.archived, vec![
+        ArchiveInfo { canister_id: archive_id, start: 1, length: 2 },
+        ArchiveInfo { canister_id: archive_id, start: 3, length: 2 },
+      ], "get_user_transactions did not point at the archived records of each archive");
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+      assert_eq!(get_archives().len(), 2, "archives were not restored after upgrade");
+      assert_eq!(history_size(), 7, "history size was not restored after upgrade");
+      assert_eq!(get_archive_options().max_records_per_archive, 3, "archive options were not restored after upgrade");
+      assert_eq!(verify_chain(5, 10), Ok(2), "the chain did not verify once its start was archived");
+      assert!(verify_chain(0, 10).is_err(), "archived blocks were verified locally");
+      let blocks = get_blocks(0, 10);
+      assert_eq!(blocks.first_index, 5, "get_blocks did not start at the first local record");
+      assert_eq!(blocks.archived.len(), 2, "get_blocks did not point at both archives");
+    }
+
+    #[test]
+    fn archive_install_failure_test() {
+      let archive_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("create_canister").response(WithCanisterId { canister_id: archive_id }))
+      .with_handler(RawHandler::raw(Box::new(|_, _, _, _| Err((RejectionCode::CanisterError, "out of cycles".to_string())))))
+      .inject();
+
+      initialize_tests();
+      set_archive_options(ArchiveOptions {
+        trigger_threshold: 2,
+        num_records_to_archive: 1,
+        max_records_per_archive: 10,
+        cycles_for_archive_creation: 1_000_000,
+      }).unwrap();
+      set_archive_wasm(vec![0, 97, 115, 109]).unwrap();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert!(get_archives().is_empty(), "an archive was added although it could not be installed");
+      assert_eq!(storage::local_op_count(), 2, "records were removed although they were not archived");
+
+      let watcher = ctx.watch();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert!(watcher.is_method_called("install_code"), "the failed round did not end");
+      assert!(!watcher.is_method_called("create_canister"), "the canister created before was not reused");
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+
+      ctx.clear_handlers();
+      ctx.use_handler(Method::new().name("install_code"));
+      ctx.use_handler(Method::new().name("append"));
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert_eq!(get_archives(), vec![ArchiveInfo { canister_id: archive_id, start: 0, length: 1 }], "the uninstalled archive was not kept across upgrade");
+      assert!(ic::get::<Archives>().uninstalled.is_none(), "the installed archive is still waiting for its code");
+    }
+
+    #[test]
+    fn archive_upgrade_test() {
+      let archive_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("create_canister").response(WithCanisterId { canister_id: archive_id }))
+      .with_handler(Method::new().name("install_code"))
+      .with_handler(Method::new().name("append"))
+      .inject();
+
+      initialize_tests();
+      set_archive_options(ArchiveOptions {
+        trigger_threshold: 2,
+        num_records_to_archive: 1,
+        max_records_per_archive: 10,
+        cycles_for_archive_creation: 1_000_000,
+      }).unwrap();
+      set_archive_wasm(vec![0, 97, 115, 109]).unwrap();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert_eq!(get_archives(), vec![ArchiveInfo { canister_id: archive_id, start: 0, length: 1 }], "no archive was spawned");
+      assert!(ic::get::<Archives>().outdated.is_empty(), "a freshly installed archive is waiting for an upgrade");
+
+      // the archive runs the code of the previous version
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+      assert_eq!(ic::get::<Archives>().outdated, vec![archive_id], "the archive was not marked for upgrade");
+      ctx.clear_handlers();
+      ctx.use_handler(RawHandler::raw(Box::new(|_, _, _, _| Err((RejectionCode::CanisterError, "out of cycles".to_string())))));
+      let watcher = ctx.watch();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert!(watcher.is_method_called("install_code"), "the archive was not upgraded before the next append");
+      assert!(!watcher.is_method_called("append"), "records were appended to an archive that failed to upgrade");
+      assert_eq!(get_archives()[0].length, 1, "records were archived to an archive that failed to upgrade");
+      assert_eq!(ic::get::<Archives>().outdated, vec![archive_id], "the archive that failed to upgrade is no longer marked");
+
+      let wasm = vec![0, 97, 115, 109, 1];
+      set_archive_wasm(wasm.clone()).unwrap();
+      ctx.clear_handlers();
+      ctx.use_handler(Method::new().name("install_code").expect_arguments((InstallCodeArgument {
+        mode: InstallMode::Upgrade,
+        canister_id: archive_id,
+        wasm_module: wasm,
+        arg: candid::encode_args(()).unwrap(),
+      },)));
+      ctx.use_handler(Method::new().name("append"));
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      ctx.call_state_reset();
+      assert!(ic::get::<Archives>().outdated.is_empty(), "the upgraded archive is still marked");
+      assert_eq!(get_archives()[0].length, 2, "records were not appended once the archive was upgraded");
+    }
+
+    #[test]
+    fn ownership_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      let txid = propose_owner(john()).unwrap();
+      let record = found_transaction(txid);
+      assert_eq!((record.op, record.to), (Operation::ProposeOwner, Account::from(john())), "the proposal was not recorded");
+      assert_eq!(get_token_info().metadata.pending_owner, Some(john()), "get_token_info did not expose the pending owner");
+      ctx.update_caller(bob());
+      assert_eq!(accept_ownership(), Err(TxError::Unauthorized), "ownership was accepted by a principal that was not proposed");
+
+      ctx.update_caller(alice());
+      grant_role(bob(), Role::Owner).unwrap();
+      ctx.update_caller(bob());
+      assert_eq!(propose_owner(bob()), Err(TxError::Unauthorized), "a holder of the owner role proposed an owner");
+      assert_eq!(cancel_ownership_transfer(), Err(TxError::Unauthorized), "a holder of the owner role cancelled the transfer");
+
+      ctx.update_caller(alice());
+      propose_owner(bob()).unwrap();
+      let txid = cancel_ownership_transfer().unwrap();
+      let record = found_transaction(txid);
+      assert_eq!((record.op, record.to), (Operation::CancelOwnershipTransfer, Account::from(bob())), "the cancellation was not recorded");
+      assert_eq!(get_token_info().metadata.pending_owner, None, "the pending owner was not cleared");
+      let history = history_size();
+      assert!(matches!(cancel_ownership_transfer(), Err(TxError::Other { code: ERR_NO_PENDING_OWNER, .. })), "a transfer was cancelled while none was pending");
+      assert_eq!(history_size(), history, "cancelling without a pending owner was recorded");
+      ctx.update_caller(bob());
+      assert_eq!(accept_ownership(), Err(TxError::Unauthorized), "a cancelled transfer was accepted");
+      assert_eq!(owner(), alice(), "ownership moved without being accepted");
+
+      ctx.update_caller(alice());
+      propose_owner(john()).unwrap();
+      ctx.update_caller(john());
+      let txid = accept_ownership().unwrap();
+      let record = found_transaction(txid);
+      assert_eq!((record.op, record.from, record.to), (Operation::AcceptOwnership, Account::from(alice()), Account::from(john())), "the ownership change was not recorded");
+      assert_eq!(owner(), john(), "ownership did not move once accepted");
+    }
+
+    #[test]
+    fn pause_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      approve(bob(), Nat::from(100)).map_err(|err| println!("{:?}", err)).ok();
+      let txid = pause(PauseFlags { transfer: true, mint: true, ..Default::default() }).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Pause(PauseFlags { transfer: true, mint: true, ..Default::default() }), "the pause was not recorded");
+      assert_eq!(transfer(bob(), Nat::from(10), None, None), Err(TxError::TemporarilyUnavailable), "a transfer went through while transfers were paused");
+      assert_eq!(mint(bob(), Nat::from(10)), Err(TxError::TemporarilyUnavailable), "a mint went through while mints were paused");
+      assert!(icrc1_transfer(TransferArg {
+        from_subaccount: None,
+        to: bob().into(),
+        amount: Nat::from(10),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+      }).is_err(), "an icrc1 transfer went through while transfers were paused");
+      assert!(burn(Nat::from(10)).is_ok(), "a burn was rejected while only transfers and mints were paused");
+      assert!(get_token_info().paused.transfer, "get_token_info did not report the paused transfers");
+
+      ctx.update_caller(bob());
+      assert!(transfer_from(alice(), john(), Nat::from(10), None, None).is_ok(), "a transfer_from was rejected while only transfers and mints were paused");
+      assert_eq!(unpause(PauseFlags { transfer: true, ..Default::default() }), Err(TxError::Unauthorized), "unpause did not reject a caller that is not a pauser");
+
+      ctx.update_caller(alice());
+      grant_role(bob(), Role::Pauser).unwrap();
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+      assert_eq!(get_token_info().paused, PauseFlags { transfer: true, mint: true, ..Default::default() }, "the pause was not restored after upgrade");
+
+      ctx.update_caller(bob());
+      let txid = unpause(PauseFlags { transfer: true, ..Default::default() }).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Unpause(PauseFlags { transfer: true, ..Default::default() }), "the unpause was not recorded");
+      ctx.update_caller(alice());
+      assert!(transfer(john(), Nat::from(1), None, None).is_ok(), "a transfer was rejected after transfers were unpaused");
+      assert!(get_token_info().paused.mint, "unpausing transfers also unpaused mints");
+    }
+
+    #[test]
+    fn freeze_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      transfer(bob(), Nat::from(150), None, None).map_err(|err| println!("{:?}", err)).ok();
+      approve(bob(), Nat::from(100)).map_err(|err| println!("{:?}", err)).ok();
+      ctx.update_caller(bob());
+      stake(Nat::from(30)).unwrap();
+      unstake(Nat::from(20)).unwrap();
+      ctx.update_caller(alice());
+      let txid = freeze(bob()).unwrap();
+      let record = found_transaction(txid);
+      assert_eq!((record.op, record.to), (Operation::Freeze, Account::from(bob())), "the freeze was not recorded");
+      assert!(freeze(bob()).is_err(), "a frozen principal was frozen again");
+      assert!(is_frozen(bob()), "is_frozen did not report the frozen principal");
+      assert_eq!(get_frozen(), vec![bob()], "get_frozen did not list the frozen principal");
+      assert!(matches!(transfer(bob(), Nat::from(10), None, None), Err(TxError::Other { code: ERR_FROZEN, .. })), "a frozen principal received a transfer");
+      assert!(approve(bob(), Nat::from(10)).is_err(), "a frozen principal was approved");
+      assert!(mint(bob(), Nat::from(10)).is_err(), "a frozen principal received a mint");
+
+      ctx.update_caller(bob());
+      assert!(transfer(john(), Nat::from(10), None, None).is_err(), "a frozen principal sent a transfer");
+      assert!(transfer_from(alice(), john(), Nat::from(10), None, None).is_err(), "a frozen principal spent an allowance");
+      assert!(burn(Nat::from(10)).is_err(), "a frozen principal burned tokens");
+      assert_eq!(wipe_frozen_balance(bob().into()), Err(TxError::Unauthorized), "wipeFrozenBalance did not reject a caller that is not the owner");
+
+      ctx.update_caller(alice());
+      assert!(wipe_frozen_balance(john().into()).is_err(), "the balance of a principal that is not frozen was wiped");
+      let supply = total_supply();
+      let txid = wipe_frozen_balance(bob().into()).unwrap();
+      let record = found_transaction(txid);
+      assert_eq!(record.op, Operation::WipeFrozenBalance, "the wipe was not recorded");
+      assert_eq!(record.amount, 150, "the wipe did not record the wiped balance and stake");
+      assert_eq!(balance_of(bob()), 0, "the frozen balance was not wiped");
+      assert_eq!(get_stake(bob()).staked, 0, "the frozen stake was not wiped");
+      assert!(get_stake(bob()).unbonding.is_empty(), "the frozen unbonding amounts were not wiped");
+      assert_eq!(get_staking_pool().total_staked, 0, "the wiped stake was left in the pool");
+      assert_eq!(total_supply(), supply - 150u64, "the wiped balance was not taken out of the supply");
+
+      let txid = unfreeze(bob()).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Unfreeze, "the unfreeze was not recorded");
+      assert!(transfer(bob(), Nat::from(10), None, None).is_ok(), "an unfrozen principal could not receive a transfer");
+    }
+
+    #[test]
+    fn supply_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      assert!(matches!(set_max_supply(Some(Nat::from(999))), Err(TxError::Other { code: ERR_INVALID_ARGUMENT, .. })), "the max supply was set below the total supply");
+      set_max_supply(Some(Nat::from(1_100))).unwrap();
+      assert_eq!(get_max_supply(), Some(Nat::from(1_100)), "get_max_supply did not return the cap");
+      assert!(matches!(mint(bob(), Nat::from(101)), Err(TxError::Other { code: ERR_MAX_SUPPLY_EXCEEDED, .. })), "a mint went over the max supply");
+      assert!(mint(bob(), Nat::from(100)).is_ok(), "a mint up to the max supply was rejected");
+      set_max_supply(None).unwrap();
+
+      grant_role(bob(), Role::Minter).unwrap();
+      set_minter_quota(bob(), Some(Nat::from(50))).unwrap();
+      ctx.update_caller(bob());
+      mint(john(), Nat::from(30)).unwrap();
+      assert_eq!(get_minter_quota(bob()).unwrap().remaining, 20, "the quota was not decremented by the mint");
+      assert!(matches!(mint(john(), Nat::from(21)), Err(TxError::Other { code: ERR_MINT_QUOTA_EXHAUSTED, .. })), "a mint went over the daily quota");
+
+      // a quota last used on an earlier day starts over
+      storage::set_minter_quota(bob(), Some(MinterQuota { daily_limit: Nat::from(50), remaining: Nat::from(0), day: 0 }));
+      assert_eq!(get_minter_quota(bob()).unwrap().remaining, 50, "the quota did not reset on a new day");
+      assert!(mint(john(), Nat::from(50)).is_ok(), "a mint within a reset quota was rejected");
+
+      ctx.update_caller(alice());
+      set_minter_quota(bob(), None).unwrap();
+      assert_eq!(get_minter_quota(bob()), None, "the quota was not lifted");
+      assert!(mint(john(), Nat::from(1_000)).is_ok(), "the owner was limited by a quota it does not have");
+    }
+
+    #[test]
+    fn burn_from_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      approve(bob(), Nat::from(100)).map_err(|err| println!("{:?}", err)).ok();
+      let supply = total_supply();
+
+      ctx.update_caller(bob());
+      let txid = burn_from(alice(), Nat::from(50)).unwrap();
+      let record = found_transaction(txid);
+      assert_eq!(record.op, Operation::Burn, "burn_from did not record a burn");
+      assert_eq!(record.caller, Some(bob()), "burn_from did not record the spender as caller");
+      assert_eq!(record.from, alice().into(), "burn_from did not burn from the approving account");
+      assert_eq!(total_supply(), supply - 50u64, "burn_from did not take the amount out of the supply");
+      assert_eq!(allowance(alice(), bob()), 51, "burn_from did not spend the allowance");
+      assert_eq!(burn_from(alice(), Nat::from(52)), Err(TxError::InsufficientAllowance), "burn_from burned more than the allowance");
+
+      ctx.update_caller(john());
+      assert_eq!(burn_from(alice(), Nat::from(10)), Err(TxError::InsufficientAllowance), "burn_from burned without an allowance");
+    }
+
+    #[test]
+    fn batch_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      let item = |to: Principal, amount: u64| BatchItem { to: to.into(), amount: Nat::from(amount) };
+      let txids = batch_transfer(vec![item(bob(), 100), item(john(), 200)]).unwrap();
+      assert_eq!(txids, vec![1, 2], "batch_transfer did not return the indices of its transfers");
+      assert_eq!(found_transaction(2).to, john().into(), "batch_transfer did not record each transfer");
+      assert_eq!(balance_of(alice()), 698, "batch_transfer did not charge a fee per item");
+      assert_eq!(balance_of(john()), 200, "batch_transfer did not credit every recipient");
+
+      // 349 + 349 and two fees are one more than alice holds
+      assert_eq!(batch_transfer(vec![item(bob(), 349), item(john(), 349)]), Err(TxError::InsufficientFunds { balance: Nat::from(698) }), "batch_transfer went over the balance");
+      assert_eq!(balance_of(bob()), 100, "a failed batch_transfer was partly applied");
+      assert_eq!(history_size(), 3, "a failed batch_transfer was recorded");
+
+      set_max_supply(Some(Nat::from(1_100))).unwrap();
+      assert!(batch_mint(vec![item(bob(), 50), item(john(), 51)]).is_err(), "batch_mint went over the max supply");
+      assert_eq!(balance_of(bob()), 100, "a failed batch_mint was partly applied");
+      let txids = batch_mint(vec![item(bob(), 50), item(john(), 50)]).unwrap();
+      assert_eq!(txids, vec![3, 4], "batch_mint did not return the indices of its mints");
+      assert_eq!(total_supply(), 1_100, "batch_mint did not mint every item");
+
+      let too_long: Vec<BatchItem> = (0..=MAX_BATCH_LEN).map(|_| item(bob(), 1)).collect();
+      assert!(batch_transfer(too_long.clone()).is_err(), "batch_transfer accepted more than MAX_BATCH_LEN items");
+      assert!(batch_mint(too_long).is_err(), "batch_mint accepted more than MAX_BATCH_LEN items");
+      assert_eq!(history_size(), 5, "a batch over MAX_BATCH_LEN was recorded");
+
+      ctx.update_caller(bob());
+      assert_eq!(batch_mint(vec![item(bob(), 1)]), Err(TxError::Unauthorized), "batch_mint did not reject a caller that is not a minter");
+    }
+
+    #[test]
+    fn fee_schedule_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      let percentage = FeeModel::Percentage { bps: 100, min: Nat::from(1), max: Some(Nat::from(5)) };
+      assert!(set_fee_schedule(FeeSchedule { transfer: Some(FeeModel::Percentage { bps: 10_001, min: Nat::from(0), max: None }), ..Default::default() }).is_err(), "a fee above 100% was accepted");
+      assert!(set_fee_schedule(FeeSchedule { transfer: Some(FeeModel::Percentage { bps: 1, min: Nat::from(2), max: Some(Nat::from(1)) }), ..Default::default() }).is_err(), "a max fee below the min fee was accepted");
+      set_fee_schedule(FeeSchedule {
+        transfer: Some(percentage.clone()),
+        transfer_from: Some(FeeModel::Flat(Nat::from(3))),
+        approve: None,
+        exempt: vec![john()],
+      }).unwrap();
+      assert_eq!(get_fee_schedule().transfer, Some(percentage), "get_fee_schedule did not return the schedule");
+
+      let txid = transfer(bob(), Nat::from(10), None, None).unwrap();
+      assert_eq!(found_transaction(txid).fee, 1, "the percentage fee was not raised to the min fee");
+      let txid = transfer(bob(), Nat::from(300), None, None).unwrap();
+      assert_eq!(found_transaction(txid).fee, 3, "the percentage fee was not charged");
+      let txid = transfer(john(), Nat::from(600), None, None).unwrap();
+      assert_eq!(found_transaction(txid).fee, 5, "the percentage fee was not capped at the max fee");
+      let txid = approve(bob(), Nat::from(20)).unwrap();
+      assert_eq!(found_transaction(txid).fee, 1, "approve did not fall back to the flat fee");
+
+      ctx.update_caller(bob());
+      let txid = transfer_from(alice(), bob(), Nat::from(10), None, None).unwrap();
+      assert_eq!(found_transaction(txid).fee, 3, "transfer_from did not charge its own fee");
+      assert!(matches!(icrc1_transfer(TransferArg {
+        from_subaccount: None,
+        to: alice().into(),
+        amount: Nat::from(200),
+        fee: Some(Nat::from(1)),
+        memo: None,
+        created_at_time: None,
+      }), Err(TransferError::BadFee { .. })), "icrc1_transfer accepted a fee that does not match the schedule");
+
+      ctx.update_caller(john());
+      let txid = transfer(bob(), Nat::from(100), None, None).unwrap();
+      assert_eq!(found_transaction(txid).fee, 0, "an exempt principal paid a fee");
+      assert_eq!(balance_of(john()), 500, "an exempt principal was charged a fee");
+
+      ctx.update_caller(alice());
+      assert_eq!(icrc1_fee(), 1, "icrc1_fee did not report the min fee of a percentage model");
+      set_fee_schedule(FeeSchedule { transfer: Some(FeeModel::Flat(Nat::from(7))), ..Default::default() }).unwrap();
+      assert_eq!(icrc1_fee(), 7, "icrc1_fee did not report the scheduled transfer fee");
+      assert!(icrc1_metadata().contains(&("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(7)))), "icrc1_metadata did not report the scheduled transfer fee");
+      assert!(icrc1_transfer(TransferArg {
+        from_subaccount: None,
+        to: bob().into(),
+        amount: Nat::from(10),
+        fee: Some(icrc1_fee()),
+        memo: None,
+        created_at_time: None,
+      }).is_ok(), "icrc1_transfer rejected the fee reported by icrc1_fee");
+      assert_eq!(found_transaction(history_size() - 1).fee, 7, "icrc1_transfer did not charge the reported fee");
+    }
+
+    #[test]
+    fn fee_recipients_test() {
+      MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      set_fee(Nat::from(4)).unwrap();
+      assert!(set_fee_recipients(vec![FeeRecipient { destination: FeeDestination::Burn, weight: 0 }]).is_err(), "a recipient without weight was accepted");
+      let heavy = FeeRecipient { destination: FeeDestination::Burn, weight: u64::MAX };
+      assert!(set_fee_recipients(vec![heavy.clone(), heavy]).is_err(), "weights overflowing their total were accepted");
+      set_fee_recipients(vec![
+        FeeRecipient { destination: FeeDestination::Account(bob().into()), weight: 2 },
+        FeeRecipient { destination: FeeDestination::Burn, weight: 1 },
+      ]).unwrap();
+      assert_eq!(get_fee_recipients().len(), 2, "get_fee_recipients did not return the recipients");
+
+      let txid = transfer(john(), Nat::from(100), None, None).unwrap();
+      let page = get_transactions(0, 10).transactions;
+      assert_eq!(txid, 3, "the transfer was not recorded after its fee shares");
+      assert_eq!(page[1].op, Operation::Fee, "the share of the treasury was not recorded");
+      assert_eq!(page[1].to, bob().into(), "the share of the treasury was not recorded for it");
+      assert_eq!(page[1].amount, 3, "the rounding remainder did not go to the first recipient");
+      assert_eq!(page[2].to, minting_account(), "the burned share was not recorded as a burn");
+      assert_eq!(page[2].amount, 1, "the burned share was not split by weight");
+      assert_eq!(page[1].amount.clone() + page[2].amount.clone(), page[3].fee, "the fee shares do not add up to the fee");
+      assert_eq!(balance_of(bob()), 3, "the treasury did not receive its share");
+      assert_eq!(total_supply(), 999, "the burned share was not taken out of the supply");
+      assert_eq!(balance_of(alice()), 896, "the fee was not charged once");
+
+      set_fee_recipients(Vec::new()).unwrap();
+      transfer(john(), Nat::from(100), None, None).unwrap();
+      assert_eq!(history_size(), 5, "fees were recorded on their own without recipients");
+    }
+
+    #[test]
+    fn fee_bucket_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      transfer(bob(), Nat::from(10), None, None).unwrap();
+      set_fee_recipients(vec![FeeRecipient { destination: FeeDestination::Bucket, weight: 1 }]).unwrap();
+      transfer(bob(), Nat::from(10), None, None).unwrap();
+      transfer(bob(), Nat::from(10), None, None).unwrap();
+      approve(bob(), Nat::from(10)).unwrap();
+
+      let today = ic::time() / NANOS_PER_DAY;
+      let stats = get_fee_stats(today, 1);
+      assert_eq!(stats.accrued, 3, "the fees were not accrued in the bucket");
+      assert_eq!(stats.collected.len(), 2, "get_fee_stats did not group the fees by operation");
+      assert_eq!((stats.collected[0].op, stats.collected[0].collected.clone()), (Operation::Transfer, Nat::from(3)), "get_fee_stats did not count every transfer fee");
+      assert_eq!((stats.collected[1].op, stats.collected[1].collected.clone()), (Operation::Approve, Nat::from(1)), "get_fee_stats did not count the approve fee");
+      assert!(get_fee_stats(today + 1, 1).collected.is_empty(), "get_fee_stats returned fees of another day");
+      assert_eq!(get_holders(0, 10).len(), 3, "the bucket shows up as a holder");
+
+      assert_eq!(claim_fees(john().into(), Nat::from(4)), Err(TxError::InsufficientFunds { balance: Nat::from(3) }), "claim_fees claimed more than was accrued");
+      let txid = claim_fees(john().into(), Nat::from(2)).unwrap();
+      let record = found_transaction(txid);
+      assert_eq!(record.op, Operation::ClaimFees, "the claim was not recorded");
+      assert_eq!(record.amount, 2, "the claim did not record its amount");
+      assert_eq!(balance_of(john()), 2, "the claimed fees were not credited");
+      assert_eq!(get_fee_stats(today, 1).accrued, 1, "the claim was not taken out of the bucket");
+
+      ctx.update_caller(bob());
+      assert_eq!(claim_fees(bob().into(), Nat::from(1)), Err(TxError::Unauthorized), "claimFees did not reject a caller that is not a fee admin");
+    }
+
+    #[test]
+    fn vesting_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      let now = ic::time();
+      let hour = 60 * 60 * 1_000_000_000;
+      assert!(mint_vested(bob(), Nat::from(100), Vesting::Linear { start: now, cliff: now + 2 * hour, end: now + hour }).is_err(), "a linear schedule with its cliff after its end was accepted");
+      mint_vested(bob(), Nat::from(100), Vesting::Cliff { at: now + hour }).unwrap();
+      assert_eq!(get_vesting_schedule(bob())[0].total, 100, "getVestingSchedule did not return the schedule");
+      let txid = mint_vested(john(), Nat::from(100), Vesting::Step { start: now - 2 * hour, interval: hour, steps: 4 }).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Mint, "the vested tokens were not minted");
+      transfer(bob(), Nat::from(10), None, None).unwrap();
+
+      ctx.update_caller(bob());
+      assert_eq!(balance_of(bob()), 110, "the vested tokens are not part of the balance");
+      assert_eq!(transfer(alice(), Nat::from(10), None, None), Err(TxError::InsufficientBalance), "locked tokens were transferred");
+      assert!(transfer(alice(), Nat::from(9), None, None).is_ok(), "unlocked tokens could not be transferred");
+      assert!(release().is_err(), "tokens were released before the cliff");
+
+      ctx.update_caller(john());
+      let txid = release().unwrap();
+      let record = found_transaction(txid);
+      assert_eq!(record.op, Operation::Release, "the release was not recorded");
+      assert_eq!(record.amount, 50, "the release did not unlock the vested steps");
+      assert_eq!(get_vesting_schedule(john())[0].released, 50, "the release was not recorded in the schedule");
+      assert!(release().is_err(), "the same vested tokens were released twice");
+      assert_eq!(transfer(alice(), Nat::from(50), None, None), Err(TxError::InsufficientBalance), "locked tokens were transferred");
+      assert!(transfer(alice(), Nat::from(49), None, None).is_ok(), "released tokens could not be transferred");
+
+      ctx.update_caller(alice());
+      mint_vested(john(), Nat::from(40), Vesting::Cliff { at: now - hour }).unwrap();
+      mint_vested(john(), Nat::from(60), Vesting::Cliff { at: now + hour }).unwrap();
+      assert_eq!(get_vesting_schedule(john()).len(), 3, "a second schedule was not added next to the first");
+      assert_eq!(get_vesting_schedules(john()), get_vesting_schedule(john()), "getVestingSchedules is not an alias of getVestingSchedule");
+      ctx.update_caller(john());
+      assert_eq!(transfer(alice(), Nat::from(1), None, None), Err(TxError::InsufficientBalance), "the locked amounts of several schedules were not added up");
+      let txid = release().unwrap();
+      assert_eq!(found_transaction(txid).amount, 40, "the release did not unlock every schedule that vested");
+      assert_eq!(get_vesting_schedule(john()).len(), 2, "a fully released schedule was kept");
+    }
+
+    #[test]
+    fn escrow_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      let now = ic::time();
+      let hour = 60 * 60 * 1_000_000_000;
+      let txid = create_escrow(bob(), Nat::from(100), now + hour, Some(john())).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::CreateEscrow(0), "the escrow was not recorded");
+      assert_eq!(balance_of(alice()), 899, "the escrowed amount and fee were not taken from the sender");
+      assert_eq!(total_supply(), 1_000, "escrowing changed the supply");
+      assert!(create_escrow(bob(), Nat::from(50), now - 1, None).is_err(), "an escrow was created with a release time in the past");
+      assert_eq!(create_escrow(bob(), Nat::from(0), now + hour, None), Err(TxError::AmountTooSmall), "an empty escrow was created");
+      assert!(create_escrow(bob(), Nat::from(50), now + hour, Some(bob())).is_err(), "the receiver was made the arbiter");
+      assert!(create_escrow(bob(), Nat::from(50), now + hour, Some(alice())).is_err(), "the sender was made the arbiter");
+      create_escrow(bob(), Nat::from(50), now + hour, None).unwrap();
+      assert_eq!(get_escrows(bob()).len(), 2, "get_escrows did not list the escrows of the receiver");
+      assert_eq!(get_escrows(john()).len(), 1, "get_escrows did not list the escrows of the arbiter");
+      // its release time has passed, the receiver can release it
+      let mut escrow = storage::escrow(1).unwrap();
+      escrow.release_time = now - 1;
+      storage::add_escrow(escrow);
+
+      ctx.update_caller(bob());
+      assert_eq!(release_escrow(0), Err(TxError::Unauthorized), "the receiver released an escrow before its release time");
+      assert_eq!(refund_escrow(1), Err(TxError::Unauthorized), "the receiver refunded an escrow");
+      ctx.update_caller(alice());
+      freeze(alice()).unwrap();
+      ctx.update_caller(bob());
+      assert!(release_escrow(1).is_err(), "an escrow from a frozen sender was released");
+      ctx.update_caller(alice());
+      unfreeze(alice()).unwrap();
+      ctx.update_caller(bob());
+      let txid = release_escrow(1).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::ReleaseEscrow(1), "the release was not recorded");
+      assert_eq!(balance_of(bob()), 50, "the released amount was not credited to the receiver");
+      assert!(release_escrow(1).is_err(), "an escrow was released twice");
+
+      ctx.update_caller(john());
+      let txid = refund_escrow(0).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::RefundEscrow(0), "the refund was not recorded");
+      assert_eq!(balance_of(alice()), 948, "the refunded amount was not credited to the sender");
+      assert!(get_escrows(alice()).is_empty(), "closed escrows are still listed");
+
+      ctx.update_caller(alice());
+      let txid = create_escrow(bob(), Nat::from(10), now + hour, None).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::CreateEscrow(2), "an escrow id was reused");
+    }
+
+    #[test]
+    fn staking_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      let hour = 60 * 60 * 1_000_000_000;
+      assert!(set_staking_config(Nat::from(10), hour).is_ok(), "the owner could not configure staking");
+      let txid = stake(Nat::from(400)).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Stake, "the stake was not recorded");
+      assert_eq!(balance_of(alice()), 600, "the staked amount was not taken from the balance");
+      assert!(stake(Nat::from(601)).is_err(), "more than the balance was staked");
+
+      // let ten seconds of emissions accrue
+      let mut pool = storage::staking_pool();
+      pool.last_update -= 10 * 1_000_000_000;
+      storage::set_staking_pool(pool);
+      let info = get_stake(alice());
+      assert_eq!(info.staked, 400, "get_stake returned the wrong staked balance");
+      assert_eq!(info.pending_rewards, 100, "rewards did not accrue at the emission rate");
+
+      let txid = claim_rewards().unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Mint, "the rewards were not minted");
+      assert_eq!(balance_of(alice()), 700, "the rewards were not credited");
+      assert_eq!(total_supply(), 1_100, "the rewards did not increase the supply");
+      assert_eq!(get_stake(alice()).pending_rewards, 0, "claimed rewards are still pending");
+      assert!(claim_rewards().is_err(), "rewards were claimed twice");
+
+      freeze(alice()).unwrap();
+      assert!(unstake(Nat::from(100)).is_err(), "a frozen principal unstaked");
+      unfreeze(alice()).unwrap();
+      let txid = unstake(Nat::from(100)).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::Unstake, "the unstake was not recorded");
+      assert!(withdraw_unstaked().is_err(), "tokens were withdrawn before they unbonded");
+      set_staking_config(Nat::from(10), 0).unwrap();
+      assert!(unstake(Nat::from(301)).is_err(), "more than the stake was unstaked");
+      unstake(Nat::from(300)).unwrap();
+      let txid = withdraw_unstaked().unwrap();
+      assert_eq!(found_transaction(txid).amount, 300, "only the unbonded amount should be withdrawn");
+      assert_eq!(balance_of(alice()), 1_000, "the withdrawn amount was not credited");
+      let info = get_stake(alice());
+      assert_eq!(info.staked, 0, "the unstaked amount is still staked");
+      assert_eq!(info.unbonding.len(), 1, "the unbonding amount was withdrawn");
+      assert_eq!(get_staking_pool().total_staked, 0, "the pool still counts unstaked tokens");
+
+      ctx.update_caller(bob());
+      assert_eq!(set_staking_config(Nat::from(1), 0), Err(TxError::Unauthorized), "a non-owner configured staking");
+    }
+
+    #[test]
+    fn roles_test() {
+      let ctx = MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      assert_eq!(get_roles(alice()).len(), 5, "the owner does not hold every role");
+      assert!(get_roles(bob()).is_empty(), "a new principal holds a role");
+
+      let txid = grant_role(bob(), Role::Minter).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::GrantRole(Role::Minter), "the grant was not recorded");
+      assert_eq!(found_transaction(txid).to, bob().into(), "the grant was not recorded for the grantee");
+      assert_eq!(get_roles(bob()), vec![Role::Minter], "get_roles did not return the granted role");
+      assert_eq!(get_role_members(Role::Minter), vec![alice(), bob()], "get_role_members did not list the owner and the minter");
+      assert!(grant_role(bob(), Role::Minter).is_err(), "a role was granted twice");
+      assert!(revoke_role(alice(), Role::Owner).is_err(), "the owner role was revoked from the owner");
+
+      ctx.update_caller(bob());
+      assert!(mint(john(), Nat::from(10)).is_ok(), "a minter could not mint");
+      assert_eq!(set_fee(Nat::from(2)), Err(TxError::Unauthorized), "a minter could set the fee");
+      assert_eq!(grant_role(bob(), Role::FeeAdmin), Err(TxError::Unauthorized), "a minter could grant roles");
+
+      ctx.update_caller(alice());
+      grant_role(john(), Role::FeeAdmin).unwrap();
+      let txid = revoke_role(bob(), Role::Minter).unwrap();
+      assert_eq!(found_transaction(txid).op, Operation::RevokeRole(Role::Minter), "the revocation was not recorded");
+      ctx.update_caller(bob());
+      assert_eq!(mint(john(), Nat::from(10)), Err(TxError::Unauthorized), "a revoked minter could still mint");
+      ctx.update_caller(john());
+      assert_eq!(set_fee(Nat::from(2)), Ok(()), "a fee admin could not set the fee");
+      assert_eq!(set_logo(String::from("logo")), Err(TxError::Unauthorized), "a fee admin could set the logo");
+    }
+
+    #[test]
+    fn blocks_test() {
+      MockContext::new()
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+      transfer(bob(), Nat::from(10), None, None).map_err(|err| println!("{:?}", err)).ok();
+      approve(bob(), Nat::from(100)).map_err(|err| println!("{:?}", err)).ok();
+
+      let response = get_blocks(0, 10);
+      assert_eq!(response.first_index, 0, "get_blocks did not start at the first record");
+      assert_eq!(response.blocks.len(), 3, "get_blocks did not return a block per record");
+      assert!(response.archived.is_empty(), "get_blocks pointed at archives while none exist");
+      let genesis: Block = candid::decode_one(&response.blocks[0]).unwrap();
+      assert_eq!(genesis.parent_hash, None, "the first block has a parent");
+      assert_eq!(genesis.transaction.op, Operation::Mint, "the first block does not hold the first record");
+      let second: Block = candid::decode_one(&response.blocks[1]).unwrap();
+      assert_eq!(second.parent_hash, Some(block_hash(&response.blocks[0]).to_vec()), "a block does not point at its parent");
+      assert_eq!(token::verify_chain(None, &response.blocks), Ok(response.tip_hash.clone()), "the chain does not end at the tip hash");
+      assert_eq!(get_blocks(1, 1).blocks, vec![response.blocks[1].clone()], "get_blocks did not return the requested range");
+      assert_eq!(verify_chain(0, 10), Ok(3), "the chain did not verify");
+      assert_eq!(verify_chain(1, 1), Ok(1), "a page in the middle of the chain did not verify");
+      assert_eq!(verify_chain(3, 10), Ok(0), "blocks past the tip were verified");
+
+      let mut swapped = response.blocks.clone();
+      swapped.swap(1, 2);
+      assert!(token::verify_chain(None, &swapped).is_err(), "reordered blocks verified");
+      let mut forged = second;
+      forged.transaction.amount = Nat::from(1_000);
+      let forged = vec![response.blocks[0].clone(), forged.encode(), response.blocks[2].clone()];
+      assert!(token::verify_chain(None, &forged).is_err(), "a forged block verified");
+    }
+
+    #[test]
+    fn icrc1_test() {
+      MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+
+      let account = |owner: Principal| Account { owner, subaccount: None };
+      let transfer_arg = |to: Account, amount: u64, fee: Option<u64>| TransferArg {
+        from_subaccount: None,
+        to,
+        amount: Nat::from(amount),
+        fee: fee.map(Nat::from),
+        memo: None,
+        created_at_time: None,
+      };
+
+      assert_eq!(icrc1_name(), name(), "icrc1_name did not return the token name");
+      assert_eq!(icrc1_symbol(), symbol(), "icrc1_symbol did not return the token symbol");
+      assert_eq!(icrc1_decimals(), 2, "icrc1_decimals did not return the correct value");
+      assert_eq!(icrc1_fee(), 1, "icrc1_fee did not return the correct value");
+      assert_eq!(icrc1_total_supply(), 1_000, "icrc1_total_supply did not return the correct value");
+      assert!(icrc1_metadata().contains(&("icrc1:symbol".to_string(), MetadataValue::Text("TOKEN".to_string()))), "icrc1_metadata did not contain the symbol");
+      assert_eq!(icrc1_supported_standards()[0].name, "ICRC-1", "icrc1_supported_standards did not list ICRC-1");
+      assert_eq!(icrc1_balance_of(account(alice())), 1_000, "icrc1_balance_of did not return the correct value");
+      assert_eq!(icrc1_balance_of(Account { owner: alice(), subaccount: Some(vec![0; 32]) }), 1_000, "the all-zero subaccount is not the default subaccount");
+      assert_eq!(icrc1_balance_of(Account { owner: alice(), subaccount: Some(vec![1; 32]) }), 0, "a non-default subaccount has a balance");
+
+      assert_eq!(icrc1_transfer(transfer_arg(account(bob()), 10, None)), Ok(Nat::from(1)), "icrc1_transfer did not return the transaction index");
+      assert_eq!(balance_of(alice()), 989, "icrc1_transfer did not charge the amount and fee");
+      assert_eq!(balance_of(bob()), 10, "icrc1_transfer did not credit the receiver");
+      assert_eq!(found_transaction(1).op, Operation::Transfer, "icrc1_transfer did not record a transfer");
+      assert_eq!(icrc1_transfer(transfer_arg(account(bob()), 10, Some(1))), Ok(Nat::from(2)), "icrc1_transfer rejected the expected fee");
+      assert_eq!(icrc1_transfer(transfer_arg(account(bob()), 10, Some(5))), Err(TransferError::BadFee { expected_fee: Nat::from(1) }), "icrc1_transfer accepted a wrong fee");
+      assert_eq!(icrc1_transfer(transfer_arg(account(bob()), 10_000, None)), Err(TransferError::InsufficientFunds { balance: Nat::from(978) }), "icrc1_transfer allowed an overdraft");
+
+      let minting_account = icrc1_minting_account().unwrap();
+      assert_eq!(icrc1_transfer(transfer_arg(minting_account.clone(), 0, None)), Err(TransferError::BadBurn { min_burn_amount: Nat::from(1) }), "icrc1_transfer burned less than the fee");
+      assert_eq!(icrc1_transfer(transfer_arg(minting_account.clone(), 10, Some(1))), Err(TransferError::BadFee { expected_fee: Nat::from(0) }), "icrc1_transfer charged a fee on a burn");
+      assert_eq!(icrc1_transfer(transfer_arg(minting_account, 8, None)), Ok(Nat::from(3)), "icrc1_transfer did not burn");
+      assert_eq!(balance_of(alice()), 970, "burning through icrc1_transfer did not debit the caller");
+      assert_eq!(icrc1_total_supply(), 992, "burning through icrc1_transfer did not reduce the supply");
+      assert_eq!(found_transaction(3).op, Operation::Burn, "icrc1_transfer did not record a burn");
+    }
+
+    #[test]
+    fn icrc2_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+
+      let account = |owner: Principal| Account { owner, subaccount: None };
+      let approve_arg = |amount: u64, expected_allowance: Option<u64>, expires_at: Option<u64>| ApproveArgs {
+        from_subaccount: None,
+        spender: account(bob()),
+        amount: Nat::from(amount),
+        expected_allowance: expected_allowance.map(Nat::from),
+        expires_at,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+      };
+      let transfer_from_arg = |amount: u64| TransferFromArgs {
+        spender_subaccount: None,
+        from: account(alice()),
+        to: account(john()),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+      };
+      let allowance_arg = AllowanceArgs { account: account(alice()), spender: account(bob()) };
+      let later = ic::time() + 3_600_000_000_000;
+
+      assert_eq!(icrc2_approve(approve_arg(100, None, Some(later))), Ok(Nat::from(1)), "icrc2_approve did not return the transaction index");
+      assert_eq!(icrc2_allowance(allowance_arg.clone()), Allowance { allowance: Nat::from(100), expires_at: Some(later) }, "icrc2_approve did not set the exact allowance");
+      assert_eq!(allowance(alice(), bob()), 100, "icrc2_approve is not visible through the DIP20 allowance");
+      assert_eq!(balance_of(alice()), 999, "icrc2_approve did not charge the fee");
+      assert_eq!(icrc2_approve(approve_arg(50, Some(10), None)), Err(ApproveError::AllowanceChanged { current_allowance: Nat::from(100) }), "icrc2_approve ignored expected_allowance");
+      assert!(matches!(icrc2_approve(approve_arg(50, None, Some(1))), Err(ApproveError::Expired { .. })), "icrc2_approve accepted an expiry in the past");
+      assert_eq!(icrc2_approve(approve_arg(60, Some(100), None)), Ok(Nat::from(2)), "icrc2_approve rejected the expected allowance");
+      assert_eq!(icrc2_allowance(allowance_arg.clone()).expires_at, None, "icrc2_approve did not replace the expiry");
+      assert!(matches!(icrc2_approve(ApproveArgs { spender: account(alice()), ..approve_arg(1, None, None) }), Err(ApproveError::GenericError { .. })), "icrc2_approve allowed approving oneself");
+
+      ctx.update_caller(bob());
+      assert_eq!(icrc2_transfer_from(transfer_from_arg(100)), Err(TransferFromError::InsufficientAllowance { allowance: Nat::from(60) }), "icrc2_transfer_from spent more than the allowance");
+      assert_eq!(icrc2_transfer_from(TransferFromArgs { fee: Some(Nat::from(3)), ..transfer_from_arg(10) }), Err(TransferFromError::BadFee { expected_fee: Nat::from(1) }), "icrc2_transfer_from accepted a wrong fee");
+      assert_eq!(icrc2_transfer_from(transfer_from_arg(10)), Ok(Nat::from(3)), "icrc2_transfer_from did not return the transaction index");
+      assert_eq!(balance_of(john()), 10, "icrc2_transfer_from did not credit the receiver");
+      assert_eq!(balance_of(alice()), 987, "icrc2_transfer_from did not debit the amount and fee");
+      assert_eq!(icrc2_allowance(allowance_arg.clone()).allowance, 49, "icrc2_transfer_from did not take the amount and fee out of the allowance");
+      assert_eq!(found_transaction(3).op, Operation::TransferFrom, "icrc2_transfer_from did not record a transferFrom");
+      assert_eq!(found_transaction(3).caller, Some(bob()), "icrc2_transfer_from did not record the spender");
+
+      // expired allowances read as zero and cannot be spent
+      storage::set_allowance(alice().into(), bob().into(), Allowance { allowance: Nat::from(40), expires_at: Some(1) });
+      assert_eq!(icrc2_allowance(allowance_arg), Allowance { allowance: Nat::from(0), expires_at: None }, "an expired allowance was returned");
+      assert!(get_user_approvals(alice()).is_empty(), "getUserApprovals returned an expired allowance");
+      assert_eq!(icrc2_transfer_from(transfer_from_arg(10)), Err(TransferFromError::InsufficientAllowance { allowance: Nat::from(0) }), "icrc2_transfer_from spent an expired allowance");
+      assert_eq!(transfer_from(alice(), john(), Nat::from(10), None, None), Err(TxError::InsufficientAllowance), "transferFrom spent an expired allowance");
+    }
+
+    #[test]
+    fn subaccount_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+
+      let savings = Account { owner: bob(), subaccount: Some(vec![1; 32]) };
+      let transfer_arg = |from_subaccount: Option<Subaccount>, to: Account, amount: u64| TransferArg {
+        from_subaccount,
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+      };
+
+      assert_eq!(transfer_to_account(None, savings.clone(), Nat::from(100), None, None), Ok(1), "transferToAccount did not return the transaction index");
+      assert_eq!(balance_of_account(savings.clone()), 100, "balanceOfAccount did not return the subaccount balance");
+      assert_eq!(icrc1_balance_of(savings.clone()), 100, "icrc1_balance_of did not return the subaccount balance");
+      assert_eq!(balance_of(bob()), 0, "a subaccount transfer credited the default subaccount");
+      assert_eq!(found_transaction(1).to, savings, "the transfer record does not carry the subaccount");
+      assert_eq!(get_holders(0, 10).len(), 3, "getHolders did not list the subaccount");
+
+      ctx.update_caller(bob());
+      assert_eq!(transfer(alice(), Nat::from(10), None, None), Err(TxError::InsufficientBalance), "transfer spent a subaccount balance from the default subaccount");
+      assert_eq!(icrc1_transfer(transfer_arg(savings.subaccount.clone(), Account::from(bob()), 30)), Ok(Nat::from(2)), "icrc1_transfer did not spend from a subaccount");
+      assert_eq!(balance_of(bob()), 30, "icrc1_transfer did not credit the default subaccount");
+      assert_eq!(balance_of_account(savings.clone()), 69, "icrc1_transfer did not debit the subaccount");
+      assert_eq!(icrc1_transfer(transfer_arg(Some(vec![0; 32]), savings.clone(), 10)), Ok(Nat::from(3)), "the all-zero subaccount is not the default subaccount");
+      assert_eq!(found_transaction(3).from, Account::from(bob()), "the all-zero subaccount was not recorded as the default subaccount");
+      assert_eq!(get_user_transaction_amount(bob()), 3, "getUserTransactionAmount did not count subaccount transactions");
+
+      // allowances are held per pair of accounts
+      let approve_arg = ApproveArgs {
+        from_subaccount: savings.subaccount.clone(),
+        spender: Account::from(john()),
+        amount: Nat::from(20),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+      };
+      assert_eq!(icrc2_approve(approve_arg), Ok(Nat::from(4)), "icrc2_approve did not approve from a subaccount");
+      assert_eq!(allowance(bob(), john()), 0, "a subaccount allowance was granted over the default subaccount");
+      ctx.update_caller(john());
+      let transfer_from_arg = TransferFromArgs {
+        spender_subaccount: None,
+        from: savings.clone(),
+        to: Account::from(john()),
+        amount: Nat::from(5),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+      };
+      assert_eq!(icrc2_transfer_from(transfer_from_arg), Ok(Nat::from(5)), "icrc2_transfer_from did not spend from a subaccount");
+      assert_eq!(balance_of(john()), 5, "icrc2_transfer_from did not credit the receiver");
+      assert_eq!(balance_of_account(savings.clone()), 72, "icrc2_transfer_from did not debit the subaccount");
+      let malformed = Account { owner: bob(), subaccount: Some(vec![1; 8]) };
+      assert_eq!(balance_of_account(malformed.clone()), 0, "balanceOfAccount did not read a malformed subaccount as empty");
+      assert!(matches!(transfer_to_account(None, malformed.clone(), Nat::from(1), None, None), Err(TxError::Other { code: ERR_INVALID_SUBACCOUNT, .. })), "transferToAccount accepted a malformed subaccount");
+      assert!(matches!(icrc1_transfer(transfer_arg(malformed.subaccount.clone(), Account::from(john()), 1)), Err(TransferError::GenericError { .. })), "icrc1_transfer accepted a malformed subaccount");
+      assert!(balance_of_certified(malformed).is_err(), "balanceOfCertified accepted a malformed subaccount");
+
+      pre_upgrade();
+      ctx.clear_storage();
+      post_upgrade();
+      assert_eq!(balance_of_account(savings), 72, "subaccount balances were not kept across upgrade");
+      assert_eq!(balance_of(bob()), 19, "default subaccount balances were not kept across upgrade");
+    }
+
+    #[test]
+    fn dedup_test() {
+      let ctx = MockContext::new()
+      .with_balance(100_000)
+      .with_caller(alice())
+      .inject();
+
+      initialize_tests();
+
+      let now = ic::time();
+      let invoice = Some(b"invoice-42".to_vec());
+      assert_eq!(transfer(bob(), Nat::from(10), invoice.clone(), Some(now)), Ok(1), "transfer with a memo did not go through");
+      assert_eq!(found_transaction(1).memo, invoice, "transfer did not record the memo");
+      assert_eq!(found_transaction(1).created_at_time, Some(now), "transfer did not record created_at_time");
+      assert_eq!(transfer(bob(), Nat::from(10), invoice.clone(), Some(now)), Err(TxError::Duplicate { duplicate_of: 1 }), "a retried transfer was not deduplicated");
+      assert_eq!(balance_of(bob()), 10, "a duplicate transfer moved tokens");
+      assert_eq!(transfer(bob(), Nat::from(10), Some(b"invoice-43".to_vec()), Some(now)), Ok(2), "a transfer with another memo was deduplicated");
+      assert_eq!(transfer(bob(), Nat::from(10), invoice.clone(), None), Ok(3), "a transfer without created_at_time was deduplicated");
+      assert_eq!(transfer(bob(), Nat::from(10), invoice.clone(), None), Ok(4), "a transfer without created_at_time was deduplicated");
+      assert_eq!(transfer(bob(), Nat::from(10), None, Some(now - dedup::TX_WINDOW - dedup::PERMITTED_DRIFT - 1)), Err(TxError::TooOld), "a transfer older than the window was accepted");
+      assert!(matches!(transfer(bob(), Nat::from(10), None, Some(now + 2 * dedup::PERMITTED_DRIFT)), Err(TxError::CreatedInFuture { .. })), "a transfer from the future was accepted");
+      assert!(matches!(transfer(bob(), Nat::from(10), Some(vec![0; 33]), None), Err(TxError::Other { code: ERR_MEMO_TOO_LONG, .. })), "a memo over the length limit was accepted");
+
+      let transfer_arg = TransferArg {
+        from_subaccount: None,
+        to: Account::from(bob()),
+        amount: Nat::from(10),
+        fee: None,
+        memo: invoice.clone(),
+        created_at_time: Some(now),
+      };
+      assert_eq!(icrc1_transfer(transfer_arg.clone()), Err(TransferError::Duplicate { duplicate_of: Nat::from(1) }), "icrc1_transfer did not share the deduplication of transfer");
+      assert_eq!(icrc1_transfer(TransferArg { created_at_time: Some(now + 1), ..transfer_arg }), Ok(Nat::from(5)), "icrc1_transfer deduplicated another created_at_time");
+
+      // transfer_from is deduplicated apart from transfer
+      storage::set_allowance(alice().into(), bob().into(), Allowance { allowance: Nat::from(100), expires_at: None });
+      ctx.update_caller(bob());
+      assert_eq!(transfer_from(alice(), bob(), Nat::from(10), invoice.clone(), Some(now)), Ok(6), "transfer_from was deduplicated against a transfer");
+      assert_eq!(transfer_from(alice(), bob(), Nat::from(10), invoice, Some(now)), Err(TxError::Duplicate { duplicate_of: 6 }), "a retried transfer_from was not deduplicated");
+
+      // hashes are forgotten once they leave the window
+      storage::add_deduplicated([7; 32], 0, 1);
+      transfer(alice(), Nat::from(1), None, None).ok();
+      assert_eq!(storage::deduplicated(&[7; 32]), None, "an expired hash was kept");
+      assert_eq!(storage::deduplicated(&[0; 32]), None, "deduplicated returned an unknown hash");
+    }
+
     #[test]
     fn permission_tests() {
       MockContext::new()
@@ -748,9 +3811,13 @@ mod tests {
 
       initialize_tests();
 
-      assert_panic!(set_logo(String::from("forbidden")));
-      assert_panic!(set_fee(123));
-      assert_panic!(set_fee_to(john()));
-      assert_panic!(set_owner(bob()));
+      assert_eq!(set_logo(String::from("forbidden")), Err(TxError::Unauthorized), "setLogo did not reject a caller that is not the owner");
+      assert_eq!(set_fee(Nat::from(123)), Err(TxError::Unauthorized), "setFee did not reject a caller that is not the owner");
+      assert_eq!(set_fee_to(john()), Err(TxError::Unauthorized), "setFeeTo did not reject a caller that is not the owner");
+      assert_eq!(propose_owner(bob()), Err(TxError::Unauthorized), "proposeOwner did not reject a caller that is not the owner");
+      assert_eq!(cancel_ownership_transfer(), Err(TxError::Unauthorized), "cancelOwnershipTransfer did not reject a caller that is not the owner");
+      assert_eq!(accept_ownership(), Err(TxError::Unauthorized), "acceptOwnership did not reject a caller that was not proposed");
+      assert_eq!(set_archive_wasm(vec![0, 97, 115, 109]), Err(TxError::Unauthorized), "setArchiveWasm did not reject a caller that is not the owner");
+      assert_eq!(set_archive_options(ArchiveOptions::default()), Err(TxError::Unauthorized), "setArchiveOptions did not reject a caller that is not the owner");
     }
 }
@@ -0,0 +1,124 @@
+/**
+* Module     : staking.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Native staking. The pool emits `emission_rate` tokens per second, shared by
+// the stakers in proportion to their stake, and rewards are minted when they
+// are claimed. Emissions are tracked as rewards per staked token, scaled by
+// SCALE, so that accruing them never needs to visit every staker. Unstaked
+// tokens unbond for `unbonding_period` before they can be withdrawn.
+use crate::storage;
+use candid::{types::number::Nat, CandidType, Deserialize};
+use ic_kit::Principal;
+
+const SCALE: u64 = 1_000_000_000_000_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct StakingPool {
+    pub emission_rate: Nat,
+    pub unbonding_period: u64,
+    pub total_staked: Nat,
+    pub reward_per_token: Nat,
+    // emissions are accounted for up to here, in whole seconds
+    pub last_update: u64,
+}
+
+impl Default for StakingPool {
+    fn default() -> Self {
+        StakingPool {
+            emission_rate: Nat::from(0),
+            unbonding_period: 0,
+            total_staked: Nat::from(0),
+            reward_per_token: Nat::from(0),
+            last_update: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct Unbonding {
+    pub amount: Nat,
+    pub release_at: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct Stake {
+    pub staked: Nat,
+    // accrued and not claimed yet
+    pub rewards: Nat,
+    pub reward_per_token_paid: Nat,
+    pub unbonding: Vec<Unbonding>,
+}
+
+impl Default for Stake {
+    fn default() -> Self {
+        Stake {
+            staked: Nat::from(0),
+            rewards: Nat::from(0),
+            reward_per_token_paid: Nat::from(0),
+            unbonding: Vec::new(),
+        }
+    }
+}
+
+impl StakingPool {
+    fn elapsed_seconds(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_update) / NANOS_PER_SECOND
+    }
+
+    pub fn reward_per_token_at(&self, now: u64) -> Nat {
+        if self.total_staked == 0u64 {
+            return self.reward_per_token.clone();
+        }
+        let emitted = self.emission_rate.clone() * self.elapsed_seconds(now);
+        self.reward_per_token.clone() + emitted * SCALE / self.total_staked.clone()
+    }
+
+    /// Accounts for the emissions up to `now`, nothing is emitted while the
+    /// pool is empty.
+    pub fn update(&mut self, now: u64) {
+        self.reward_per_token = self.reward_per_token_at(now);
+        if self.total_staked == 0u64 {
+            self.last_update = now;
+        } else {
+            self.last_update += self.elapsed_seconds(now) * NANOS_PER_SECOND;
+        }
+    }
+}
+
+impl Stake {
+    pub fn pending_rewards(&self, reward_per_token: &Nat) -> Nat {
+        let owed = self.staked.clone()
+            * (reward_per_token.clone() - self.reward_per_token_paid.clone())
+            / SCALE;
+        self.rewards.clone() + owed
+    }
+
+    fn settle(&mut self, reward_per_token: &Nat) {
+        self.rewards = self.pending_rewards(reward_per_token);
+        self.reward_per_token_paid = reward_per_token.clone();
+    }
+}
+
+/// The pool and the stake of `user`, with emissions accounted for up to `now`.
+/// Changes only persist once passed to `save`.
+pub fn settle(user: &Principal, now: u64) -> (StakingPool, Stake) {
+    let mut pool = storage::staking_pool();
+    pool.update(now);
+    let mut stake = storage::stake(user).unwrap_or_default();
+    stake.settle(&pool.reward_per_token);
+    (pool, stake)
+}
+
+pub fn save(user: Principal, pool: StakingPool, stake: Stake) {
+    storage::set_staking_pool(pool);
+    if stake == Stake::default() {
+        storage::set_stake(user, None);
+    } else {
+        storage::set_stake(user, Some(stake));
+    }
+}
@@ -0,0 +1,907 @@
+/**
+* Module     : storage.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+use crate::fees::{FeeRecipients, FeeSchedule};
+use crate::escrow::Escrow;
+use crate::staking::{Stake, StakingPool};
+use crate::vesting::VestingSchedule;
+use crate::{Account, Allowance, MinterQuota, OpRecord, Role};
+use candid::{types::number::Nat, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{
+    DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell, Storable,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Never reuse or renumber these, they identify regions of stable memory.
+const UPGRADES: MemoryId = MemoryId::new(0);
+const BALANCES: MemoryId = MemoryId::new(1);
+const ALLOWANCES: MemoryId = MemoryId::new(2);
+const OPS: MemoryId = MemoryId::new(3);
+const ARCHIVE_WASM: MemoryId = MemoryId::new(4);
+const DEDUP: MemoryId = MemoryId::new(5);
+const DEDUP_QUEUE: MemoryId = MemoryId::new(6);
+const USER_OPS: MemoryId = MemoryId::new(7);
+const USER_OP_COUNTS: MemoryId = MemoryId::new(8);
+const HOLDERS: MemoryId = MemoryId::new(9);
+const BLOCK_HASHES: MemoryId = MemoryId::new(10);
+const ROLES: MemoryId = MemoryId::new(11);
+const FROZEN: MemoryId = MemoryId::new(12);
+const MINTER_QUOTAS: MemoryId = MemoryId::new(13);
+const FEE_SCHEDULE: MemoryId = MemoryId::new(14);
+const FEE_RECIPIENTS: MemoryId = MemoryId::new(15);
+const FEE_BUCKET: MemoryId = MemoryId::new(16);
+const FEE_STATS: MemoryId = MemoryId::new(17);
+const VESTING: MemoryId = MemoryId::new(18);
+const ESCROWS: MemoryId = MemoryId::new(19);
+const ESCROW_PARTIES: MemoryId = MemoryId::new(20);
+const ESCROW_IDS: MemoryId = MemoryId::new(21);
+const STAKING_POOL: MemoryId = MemoryId::new(22);
+const STAKES: MemoryId = MemoryId::new(23);
+const CERTIFIED_ENTRIES: MemoryId = MemoryId::new(24);
+const CERTIFIED_NODES: MemoryId = MemoryId::new(25);
+const VESTING_IDS: MemoryId = MemoryId::new(26);
+
+const MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+const SUBACCOUNT_LEN: usize = 32;
+// Principals are at most 29 bytes long, anything longer carries a subaccount.
+const MAX_PRINCIPAL_LEN: usize = 29;
+
+// Accounts are stored as the owner's bytes followed by the subaccount, the
+// default subaccount is left out so that keys written before subaccounts
+// existed still decode as the owner's default account.
+fn encode_account(account: &Account, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(account.owner.as_slice());
+    if let Some(subaccount) = &account.subaccount {
+        assert_eq!(subaccount.len(), SUBACCOUNT_LEN, "subaccount must be 32 bytes");
+        bytes.extend_from_slice(subaccount);
+    }
+}
+
+/// The stored form of `account`, also used as its key in the certified tree.
+pub fn account_bytes(account: &Account) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    encode_account(account, &mut bytes);
+    bytes
+}
+
+fn decode_account(bytes: &[u8]) -> Account {
+    if bytes.len() > MAX_PRINCIPAL_LEN {
+        let (owner, subaccount) = bytes.split_at(bytes.len() - SUBACCOUNT_LEN);
+        Account::new(Principal::from_slice(owner), Some(subaccount.to_vec()))
+    } else {
+        Account::from(Principal::from_slice(bytes))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct StoredPrincipal(Principal);
+
+impl Storable for StoredPrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredPrincipal(Principal::from_slice(&bytes))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_PRINCIPAL_LEN as u32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct StoredAccount(Account);
+
+impl Storable for StoredAccount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(account_bytes(&self.0))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredAccount(decode_account(&bytes))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (MAX_PRINCIPAL_LEN + SUBACCOUNT_LEN) as u32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AllowanceKey {
+    owner: Account,
+    spender: Account,
+}
+
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![0];
+        encode_account(&self.owner, &mut bytes);
+        bytes[0] = (bytes.len() - 1) as u8;
+        encode_account(&self.spender, &mut bytes);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        AllowanceKey {
+            owner: decode_account(&bytes[1..1 + len]),
+            spender: decode_account(&bytes[1 + len..]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (1 + 2 * (MAX_PRINCIPAL_LEN + SUBACCOUNT_LEN)) as u32,
+        is_fixed_size: false,
+    };
+}
+
+// Orders holders by balance, largest first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HolderKey {
+    balance: Nat,
+    account: Account,
+}
+
+impl Ord for HolderKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .balance
+            .cmp(&self.balance)
+            .then_with(|| self.account.cmp(&other.account))
+    }
+}
+
+impl PartialOrd for HolderKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Balances up to 2^256 fit in the 37 bytes a nat takes at most here.
+const MAX_HOLDER_NAT_LEN: usize = 37;
+
+impl Storable for HolderKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![0];
+        encode_account(&self.account, &mut bytes);
+        bytes[0] = (bytes.len() - 1) as u8;
+        self.balance.encode(&mut bytes).expect("failed to encode nat");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        HolderKey {
+            account: decode_account(&bytes[1..1 + len]),
+            balance: Nat::decode(&mut &bytes[1 + len..]).expect("failed to decode nat"),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (1 + MAX_PRINCIPAL_LEN + SUBACCOUNT_LEN + MAX_HOLDER_NAT_LEN) as u32,
+        is_fixed_size: false,
+    };
+}
+
+// An entry of one of the certified tries, see certification.rs. Entries of a
+// bucket are adjacent and ordered by key.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CertifiedKey {
+    tree: u8,
+    bucket: u16,
+    key: Vec<u8>,
+}
+
+impl Storable for CertifiedKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![self.tree];
+        bytes.extend_from_slice(&self.bucket.to_be_bytes());
+        bytes.extend_from_slice(&self.key);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        CertifiedKey {
+            tree: bytes[0],
+            bucket: u16::from_be_bytes([bytes[1], bytes[2]]),
+            key: bytes[3..].to_vec(),
+        }
+    }
+
+    // keys are accounts or u64 indices
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (3 + MAX_PRINCIPAL_LEN + SUBACCOUNT_LEN) as u32,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct StoredNat(Nat);
+
+impl Storable for StoredNat {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        self.0.encode(&mut bytes).expect("failed to encode nat");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredNat(Nat::decode(&mut bytes.as_ref()).expect("failed to decode nat"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// The amount keeps the plain nat encoding, entries written before allowances
+// could expire simply have no trailing expiry.
+impl Storable for Allowance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        self.allowance.encode(&mut bytes).expect("failed to encode nat");
+        if let Some(expires_at) = self.expires_at {
+            bytes.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut rest = bytes.as_ref();
+        let allowance = Nat::decode(&mut rest).expect("failed to decode nat");
+        let expires_at = if rest.is_empty() {
+            None
+        } else {
+            let mut expiry = [0u8; 8];
+            expiry.copy_from_slice(rest);
+            Some(u64::from_le_bytes(expiry))
+        };
+        Allowance {
+            allowance,
+            expires_at,
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Settings that are read and written as a whole are kept as candid.
+macro_rules! candid_storable {
+    ($type:ty, $name:literal) => {
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(candid::encode_one(self).expect(concat!("failed to encode ", $name)))
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                candid::decode_one(&bytes).expect(concat!("failed to decode ", $name))
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    };
+}
+
+candid_storable!(MinterQuota, "minter quota");
+candid_storable!(FeeSchedule, "fee schedule");
+candid_storable!(FeeRecipients, "fee recipients");
+candid_storable!(VestingSchedule, "vesting schedule");
+candid_storable!(Escrow, "escrow");
+candid_storable!(StakingPool, "staking pool");
+candid_storable!(Stake, "stake");
+
+thread_local! {
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+        MemoryManager::init(STABLE_MEMORY.with(|m| m.clone()));
+
+    static UPGRADE_PAYLOAD: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(memory(UPGRADES), Vec::new()).expect("failed to init upgrade cell"),
+    );
+
+    static BALANCE_MAP: RefCell<StableBTreeMap<StoredAccount, StoredNat, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BALANCES)));
+
+    static ALLOWANCE_MAP: RefCell<StableBTreeMap<AllowanceKey, Allowance, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ALLOWANCES)));
+
+    // Records that have not been moved to an archive canister yet, keyed by
+    // their index in the full history.
+    static OP_MAP: RefCell<StableBTreeMap<u64, OpRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(OPS)));
+
+    static ARCHIVE_WASM_CELL: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(memory(ARCHIVE_WASM), Vec::new()).expect("failed to init archive wasm cell"),
+    );
+
+    // Hashes of recent transactions that carried a created_at_time, mapped to
+    // their index, and the same hashes in the order they were added so that
+    // they can be dropped once they leave the deduplication window.
+    static DEDUP_MAP: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DEDUP)));
+
+    static DEDUP_QUEUE_MAP: RefCell<StableBTreeMap<u64, (u64, [u8; 32]), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(DEDUP_QUEUE)));
+
+    // The n-th transaction involving a principal, mapped to its index in the
+    // full history, so that a page of user history is a single range read.
+    static USER_OP_MAP: RefCell<StableBTreeMap<(StoredPrincipal, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(USER_OPS)));
+
+    static USER_OP_COUNT_MAP: RefCell<StableBTreeMap<StoredPrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(USER_OP_COUNTS)));
+
+    // Every entry of BALANCE_MAP again, ordered by balance.
+    static HOLDER_MAP: RefCell<StableBTreeMap<HolderKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(HOLDERS)));
+
+    // Hash of the block of every record, kept after the record is archived so
+    // that the chain can be continued and checked locally.
+    static BLOCK_HASH_MAP: RefCell<StableBTreeMap<u64, [u8; 32], Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(BLOCK_HASHES)));
+
+    // (role, principal) for every granted role
+    static ROLE_MAP: RefCell<StableBTreeMap<(u8, StoredPrincipal), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ROLES)));
+
+    static FROZEN_MAP: RefCell<StableBTreeMap<StoredPrincipal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(FROZEN)));
+
+    static MINTER_QUOTA_MAP: RefCell<StableBTreeMap<StoredPrincipal, MinterQuota, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(MINTER_QUOTAS)));
+
+    static FEE_SCHEDULE_CELL: RefCell<StableCell<FeeSchedule, Memory>> = RefCell::new(
+        StableCell::init(memory(FEE_SCHEDULE), FeeSchedule::default())
+            .expect("failed to init fee schedule cell"),
+    );
+
+    static FEE_RECIPIENTS_CELL: RefCell<StableCell<FeeRecipients, Memory>> = RefCell::new(
+        StableCell::init(memory(FEE_RECIPIENTS), FeeRecipients::default())
+            .expect("failed to init fee recipients cell"),
+    );
+
+    // fees accrued and not claimed yet, they are not part of any balance
+    static FEE_BUCKET_CELL: RefCell<StableCell<StoredNat, Memory>> = RefCell::new(
+        StableCell::init(memory(FEE_BUCKET), StoredNat(Nat::from(0)))
+            .expect("failed to init fee bucket cell"),
+    );
+
+    // (day, operation code) -> fees collected
+    static FEE_STATS_MAP: RefCell<StableBTreeMap<(u64, u8), StoredNat, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(FEE_STATS)));
+
+    // (holder, schedule id) -> schedule
+    static VESTING_MAP: RefCell<StableBTreeMap<(StoredPrincipal, u64), VestingSchedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(VESTING)));
+
+    static NEXT_VESTING_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory(VESTING_IDS), 0).expect("failed to init vesting id cell"),
+    );
+
+    static ESCROW_MAP: RefCell<StableBTreeMap<u64, Escrow, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ESCROWS)));
+
+    // (party, escrow id) for the sender, receiver and arbiter of open escrows
+    static ESCROW_PARTY_MAP: RefCell<StableBTreeMap<(StoredPrincipal, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ESCROW_PARTIES)));
+
+    // ids are never reused, even once their escrow is closed
+    static NEXT_ESCROW_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(memory(ESCROW_IDS), 0).expect("failed to init escrow id cell"),
+    );
+
+    static STAKING_POOL_CELL: RefCell<StableCell<StakingPool, Memory>> = RefCell::new(
+        StableCell::init(memory(STAKING_POOL), StakingPool::default())
+            .expect("failed to init staking pool cell"),
+    );
+
+    static STAKE_MAP: RefCell<StableBTreeMap<StoredPrincipal, Stake, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(STAKES)));
+
+    static CERTIFIED_ENTRY_MAP: RefCell<StableBTreeMap<CertifiedKey, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(CERTIFIED_ENTRIES)));
+
+    // (tree, node) -> hash, for the trie nodes whose hash differs from the
+    // one of an empty trie
+    static CERTIFIED_NODE_MAP: RefCell<StableBTreeMap<(u8, u32), [u8; 32], Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(CERTIFIED_NODES)));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(id))
+}
+
+/// Whether stable memory is laid out by the memory manager, as opposed to
+/// holding a single `stable_store` payload written by an older canister.
+/// Must be called before touching any of the stable structures.
+pub fn is_managed() -> bool {
+    STABLE_MEMORY.with(|m| {
+        if m.size() == 0 {
+            return false;
+        }
+        let mut magic = [0u8; 3];
+        m.read(0, &mut magic);
+        &magic == MANAGER_MAGIC
+    })
+}
+
+pub fn upgrade_payload() -> Vec<u8> {
+    UPGRADE_PAYLOAD.with(|c| c.borrow().get().clone())
+}
+
+pub fn set_upgrade_payload(bytes: Vec<u8>) {
+    UPGRADE_PAYLOAD.with(|c| {
+        c.borrow_mut()
+            .set(bytes)
+            .expect("failed to write upgrade payload")
+    });
+}
+
+pub fn archive_wasm() -> Vec<u8> {
+    ARCHIVE_WASM_CELL.with(|c| c.borrow().get().clone())
+}
+
+pub fn has_archive_wasm() -> bool {
+    ARCHIVE_WASM_CELL.with(|c| !c.borrow().get().is_empty())
+}
+
+pub fn set_archive_wasm(wasm: Vec<u8>) {
+    ARCHIVE_WASM_CELL.with(|c| {
+        c.borrow_mut()
+            .set(wasm)
+            .expect("failed to write archive wasm")
+    });
+}
+
+pub fn balance(account: &Account) -> Option<Nat> {
+    BALANCE_MAP.with(|b| b.borrow().get(&StoredAccount(account.clone())).map(|v| v.0))
+}
+
+/// Sets the balance of `account`, dropping the entry entirely when it reaches
+/// zero, and moves it to its new place among the holders.
+pub fn set_balance(account: Account, value: Nat) {
+    let old = BALANCE_MAP.with(|b| {
+        let mut b = b.borrow_mut();
+        if value == 0u64 {
+            b.remove(&StoredAccount(account.clone()))
+        } else {
+            b.insert(StoredAccount(account.clone()), StoredNat(value.clone()))
+        }
+    });
+    HOLDER_MAP.with(|h| {
+        let mut h = h.borrow_mut();
+        if let Some(old) = old {
+            h.remove(&HolderKey {
+                balance: old.0,
+                account: account.clone(),
+            });
+        }
+        if value != 0u64 {
+            h.insert(HolderKey { balance: value, account }, ());
+        }
+    });
+}
+
+pub fn holder_count() -> usize {
+    BALANCE_MAP.with(|b| b.borrow().len() as usize)
+}
+
+/// The holders `[start, start + limit)` ordered by balance, largest first.
+/// Walks past the first `start` holders, use `holders_after` for deep pages.
+pub fn holders(start: usize, limit: usize) -> Vec<(Account, Nat)> {
+    HOLDER_MAP.with(|h| {
+        h.borrow()
+            .iter()
+            .skip(start)
+            .take(limit)
+            .map(|(k, _)| (k.account, k.balance))
+            .collect()
+    })
+}
+
+/// The `limit` holders following `after`, the last holder of the previous
+/// page, in the order of `holders`. Starts from the first holder without it.
+pub fn holders_after(after: Option<(Account, Nat)>, limit: usize) -> Vec<(Account, Nat)> {
+    let start = match after {
+        Some((account, balance)) => std::ops::Bound::Excluded(HolderKey { balance, account }),
+        None => std::ops::Bound::Unbounded,
+    };
+    HOLDER_MAP.with(|h| {
+        h.borrow()
+            .range((start, std::ops::Bound::Unbounded))
+            .take(limit)
+            .map(|(k, _)| (k.account, k.balance))
+            .collect()
+    })
+}
+
+/// Rebuilds the holder order from the balances, for canisters whose balances
+/// were written before it was maintained.
+pub fn index_holders() {
+    if HOLDER_MAP.with(|h| h.borrow().len()) == BALANCE_MAP.with(|b| b.borrow().len()) {
+        return;
+    }
+    HOLDER_MAP.with(|h| {
+        let mut h = h.borrow_mut();
+        BALANCE_MAP.with(|b| {
+            for (account, balance) in b.borrow().iter() {
+                h.insert(
+                    HolderKey {
+                        balance: balance.0,
+                        account: account.0,
+                    },
+                    (),
+                );
+            }
+        });
+    });
+}
+
+/// The stored allowance of `spender`, whether it has expired or not.
+pub fn allowance(owner: &Account, spender: &Account) -> Option<Allowance> {
+    let key = AllowanceKey {
+        owner: owner.clone(),
+        spender: spender.clone(),
+    };
+    ALLOWANCE_MAP.with(|a| a.borrow().get(&key))
+}
+
+/// Sets the allowance of `spender` over `owner`'s tokens, dropping the entry
+/// entirely when it reaches zero.
+pub fn set_allowance(owner: Account, spender: Account, value: Allowance) {
+    let key = AllowanceKey { owner, spender };
+    ALLOWANCE_MAP.with(|a| {
+        let mut a = a.borrow_mut();
+        if value.allowance == 0u64 {
+            a.remove(&key);
+        } else {
+            a.insert(key, value);
+        }
+    });
+}
+
+pub fn allowance_count() -> usize {
+    ALLOWANCE_MAP.with(|a| a.borrow().len() as usize)
+}
+
+pub fn allowances_of(owner: &Account) -> Vec<(Account, Allowance)> {
+    // The management canister id is the empty principal, its default account
+    // sorts first.
+    let start = AllowanceKey {
+        owner: owner.clone(),
+        spender: Account::from(Principal::management_canister()),
+    };
+    ALLOWANCE_MAP.with(|a| {
+        a.borrow()
+            .range(start..)
+            .take_while(|(k, _)| k.owner == *owner)
+            .map(|(k, v)| (k.spender, v))
+            .collect()
+    })
+}
+
+pub fn append_op(record: &OpRecord) -> usize {
+    OP_MAP.with(|m| m.borrow_mut().insert(record.index as u64, record.clone()));
+    record.index
+}
+
+pub fn op(index: usize) -> Option<OpRecord> {
+    OP_MAP.with(|m| m.borrow().get(&(index as u64)))
+}
+
+/// Number of records still held by this canister.
+pub fn local_op_count() -> usize {
+    OP_MAP.with(|m| m.borrow().len() as usize)
+}
+
+pub fn ops(start: usize, limit: usize) -> Vec<OpRecord> {
+    let end = start.saturating_add(limit) as u64;
+    OP_MAP.with(|m| m.borrow().range(start as u64..end).map(|(_, v)| v).collect())
+}
+
+pub fn remove_op(index: usize) -> Option<OpRecord> {
+    OP_MAP.with(|m| m.borrow_mut().remove(&(index as u64)))
+}
+
+pub fn deduplicated(hash: &[u8; 32]) -> Option<usize> {
+    DEDUP_MAP.with(|m| m.borrow().get(hash).map(|i| i as usize))
+}
+
+pub fn add_deduplicated(hash: [u8; 32], index: usize, timestamp: u64) {
+    DEDUP_MAP.with(|m| m.borrow_mut().insert(hash, index as u64));
+    DEDUP_QUEUE_MAP.with(|q| q.borrow_mut().insert(index as u64, (timestamp, hash)));
+}
+
+/// Forgets the hashes of transactions recorded before `timestamp`.
+pub fn prune_deduplicated(timestamp: u64) {
+    DEDUP_QUEUE_MAP.with(|q| {
+        let mut q = q.borrow_mut();
+        while let Some((index, (added, hash))) = q.first_key_value() {
+            if added >= timestamp {
+                break;
+            }
+            q.remove(&index);
+            DEDUP_MAP.with(|m| m.borrow_mut().remove(&hash));
+        }
+    });
+}
+
+pub fn add_user_op(user: Principal, index: usize) {
+    let count = user_op_count(&user) as u64;
+    let user = StoredPrincipal(user);
+    USER_OP_MAP.with(|m| m.borrow_mut().insert((user, count), index as u64));
+    USER_OP_COUNT_MAP.with(|m| m.borrow_mut().insert(user, count + 1));
+}
+
+pub fn user_op_count(user: &Principal) -> usize {
+    USER_OP_COUNT_MAP.with(|m| m.borrow().get(&StoredPrincipal(*user)).unwrap_or(0) as usize)
+}
+
+/// History indices of the transactions `[start, start + limit)` of `user`.
+pub fn user_ops(user: &Principal, start: usize, limit: usize) -> Vec<usize> {
+    let user = StoredPrincipal(*user);
+    let end = start.saturating_add(limit) as u64;
+    USER_OP_MAP.with(|m| {
+        m.borrow()
+            .range((user, start as u64)..(user, end))
+            .map(|(_, index)| index as usize)
+            .collect()
+    })
+}
+
+pub fn block_hash(index: usize) -> Option<[u8; 32]> {
+    BLOCK_HASH_MAP.with(|m| m.borrow().get(&(index as u64)))
+}
+
+pub fn set_block_hash(index: usize, hash: [u8; 32]) {
+    BLOCK_HASH_MAP.with(|m| m.borrow_mut().insert(index as u64, hash));
+}
+
+pub fn has_role(role: Role, user: &Principal) -> bool {
+    ROLE_MAP.with(|m| m.borrow().contains_key(&(role as u8, StoredPrincipal(*user))))
+}
+
+/// Grants or revokes `role`, returns whether anything changed.
+pub fn set_role(role: Role, user: Principal, granted: bool) -> bool {
+    let key = (role as u8, StoredPrincipal(user));
+    ROLE_MAP.with(|m| {
+        let mut m = m.borrow_mut();
+        if granted {
+            m.insert(key, ()).is_none()
+        } else {
+            m.remove(&key).is_some()
+        }
+    })
+}
+
+pub fn role_members(role: Role) -> Vec<Principal> {
+    ROLE_MAP.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|((r, _), _)| *r == role as u8)
+            .map(|((_, user), _)| user.0)
+            .collect()
+    })
+}
+
+pub fn is_frozen(user: &Principal) -> bool {
+    FROZEN_MAP.with(|m| m.borrow().contains_key(&StoredPrincipal(*user)))
+}
+
+/// Freezes or unfreezes `user`, returns whether anything changed.
+pub fn set_frozen(user: Principal, frozen: bool) -> bool {
+    FROZEN_MAP.with(|m| {
+        let mut m = m.borrow_mut();
+        if frozen {
+            m.insert(StoredPrincipal(user), ()).is_none()
+        } else {
+            m.remove(&StoredPrincipal(user)).is_some()
+        }
+    })
+}
+
+pub fn frozen() -> Vec<Principal> {
+    FROZEN_MAP.with(|m| m.borrow().iter().map(|(user, _)| user.0).collect())
+}
+
+pub fn minter_quota(minter: &Principal) -> Option<MinterQuota> {
+    MINTER_QUOTA_MAP.with(|m| m.borrow().get(&StoredPrincipal(*minter)))
+}
+
+pub fn set_minter_quota(minter: Principal, quota: Option<MinterQuota>) {
+    MINTER_QUOTA_MAP.with(|m| match quota {
+        Some(quota) => m.borrow_mut().insert(StoredPrincipal(minter), quota),
+        None => m.borrow_mut().remove(&StoredPrincipal(minter)),
+    });
+}
+
+pub fn fee_schedule() -> FeeSchedule {
+    FEE_SCHEDULE_CELL.with(|c| c.borrow().get().clone())
+}
+
+pub fn set_fee_schedule(schedule: FeeSchedule) {
+    FEE_SCHEDULE_CELL.with(|c| {
+        c.borrow_mut()
+            .set(schedule)
+            .expect("failed to write fee schedule")
+    });
+}
+
+pub fn fee_recipients() -> FeeRecipients {
+    FEE_RECIPIENTS_CELL.with(|c| c.borrow().get().clone())
+}
+
+pub fn set_fee_recipients(recipients: FeeRecipients) {
+    FEE_RECIPIENTS_CELL.with(|c| {
+        c.borrow_mut()
+            .set(recipients)
+            .expect("failed to write fee recipients")
+    });
+}
+
+pub fn fee_bucket() -> Nat {
+    FEE_BUCKET_CELL.with(|c| c.borrow().get().0.clone())
+}
+
+pub fn set_fee_bucket(value: Nat) {
+    FEE_BUCKET_CELL.with(|c| {
+        c.borrow_mut()
+            .set(StoredNat(value))
+            .expect("failed to write fee bucket")
+    });
+}
+
+pub fn add_fee_stat(day: u64, op: u8, amount: Nat) {
+    FEE_STATS_MAP.with(|m| {
+        let mut m = m.borrow_mut();
+        let total = m.get(&(day, op)).map_or(Nat::from(0), |n| n.0);
+        m.insert((day, op), StoredNat(total + amount));
+    });
+}
+
+/// Fees collected on the days `[start, end)` as (day, operation code, amount).
+pub fn fee_stats(start: u64, end: u64) -> Vec<(u64, u8, Nat)> {
+    FEE_STATS_MAP.with(|m| {
+        m.borrow()
+            .range((start, 0)..(end, 0))
+            .map(|((day, op), amount)| (day, op, amount.0))
+            .collect()
+    })
+}
+
+pub fn next_vesting_id() -> u64 {
+    NEXT_VESTING_ID.with(|c| {
+        let id = *c.borrow().get();
+        c.borrow_mut()
+            .set(id + 1)
+            .expect("failed to write vesting id");
+        id
+    })
+}
+
+pub fn vesting_schedules(user: &Principal) -> Vec<VestingSchedule> {
+    VESTING_MAP.with(|m| {
+        m.borrow()
+            .range((StoredPrincipal(*user), 0)..=(StoredPrincipal(*user), u64::MAX))
+            .map(|(_, schedule)| schedule)
+            .collect()
+    })
+}
+
+pub fn set_vesting_schedule(user: Principal, id: u64, schedule: Option<VestingSchedule>) {
+    VESTING_MAP.with(|m| match schedule {
+        Some(schedule) => m.borrow_mut().insert((StoredPrincipal(user), id), schedule),
+        None => m.borrow_mut().remove(&(StoredPrincipal(user), id)),
+    });
+}
+
+pub fn next_escrow_id() -> u64 {
+    NEXT_ESCROW_ID.with(|c| {
+        let id = *c.borrow().get();
+        c.borrow_mut()
+            .set(id + 1)
+            .expect("failed to write escrow id");
+        id
+    })
+}
+
+pub fn escrow(id: u64) -> Option<Escrow> {
+    ESCROW_MAP.with(|m| m.borrow().get(&id))
+}
+
+pub fn add_escrow(escrow: Escrow) {
+    for party in escrow.parties() {
+        ESCROW_PARTY_MAP.with(|m| m.borrow_mut().insert((StoredPrincipal(party), escrow.id), ()));
+    }
+    ESCROW_MAP.with(|m| m.borrow_mut().insert(escrow.id, escrow));
+}
+
+pub fn remove_escrow(id: u64) {
+    if let Some(escrow) = ESCROW_MAP.with(|m| m.borrow_mut().remove(&id)) {
+        for party in escrow.parties() {
+            ESCROW_PARTY_MAP.with(|m| m.borrow_mut().remove(&(StoredPrincipal(party), id)));
+        }
+    }
+}
+
+/// Open escrows `user` sends, receives or arbitrates.
+pub fn escrows_of(user: &Principal) -> Vec<Escrow> {
+    let user = StoredPrincipal(*user);
+    let ids: Vec<u64> = ESCROW_PARTY_MAP.with(|m| {
+        m.borrow()
+            .range((user, 0)..=(user, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.into_iter().filter_map(escrow).collect()
+}
+
+pub fn staking_pool() -> StakingPool {
+    STAKING_POOL_CELL.with(|c| c.borrow().get().clone())
+}
+
+pub fn set_staking_pool(pool: StakingPool) {
+    STAKING_POOL_CELL.with(|c| {
+        c.borrow_mut()
+            .set(pool)
+            .expect("failed to write staking pool")
+    });
+}
+
+pub fn stake(user: &Principal) -> Option<Stake> {
+    STAKE_MAP.with(|m| m.borrow().get(&StoredPrincipal(*user)))
+}
+
+pub fn set_stake(user: Principal, stake: Option<Stake>) {
+    STAKE_MAP.with(|m| match stake {
+        Some(stake) => m.borrow_mut().insert(StoredPrincipal(user), stake),
+        None => m.borrow_mut().remove(&StoredPrincipal(user)),
+    });
+}
+
+/// The entries of a bucket of the certified trie `tree`, ordered by key.
+pub fn certified_bucket(tree: u8, bucket: u16) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let start = CertifiedKey {
+        tree,
+        bucket,
+        key: Vec::new(),
+    };
+    CERTIFIED_ENTRY_MAP.with(|m| {
+        m.borrow()
+            .range(start..)
+            .take_while(|(k, _)| k.tree == tree && k.bucket == bucket)
+            .map(|(k, v)| (k.key, v))
+            .collect()
+    })
+}
+
+pub fn set_certified_entry(tree: u8, bucket: u16, key: Vec<u8>, value: Option<Vec<u8>>) {
+    let key = CertifiedKey { tree, bucket, key };
+    CERTIFIED_ENTRY_MAP.with(|m| match value {
+        Some(value) => m.borrow_mut().insert(key, value),
+        None => m.borrow_mut().remove(&key),
+    });
+}
+
+pub fn certified_node(tree: u8, node: u32) -> Option<[u8; 32]> {
+    CERTIFIED_NODE_MAP.with(|m| m.borrow().get(&(tree, node)))
+}
+
+pub fn set_certified_node(tree: u8, node: u32, hash: Option<[u8; 32]>) {
+    CERTIFIED_NODE_MAP.with(|m| match hash {
+        Some(hash) => m.borrow_mut().insert((tree, node), hash),
+        None => m.borrow_mut().remove(&(tree, node)),
+    });
+}
@@ -0,0 +1,91 @@
+/**
+* Module     : vesting.rs
+* Copyright  : 2021 Rocklabs
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Rocklabs <hello@rocklabs.io>
+* Stability  : Experimental
+*/
+// Vesting schedules lock part of the default account of a principal. Tokens
+// vest over time and stay locked until the holder releases them, so the
+// locked amount is always `total - released`. A principal can hold several
+// schedules, their locked amounts add up.
+use crate::storage;
+use candid::{types::number::Nat, CandidType, Deserialize};
+use ic_kit::Principal;
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum Vesting {
+    // everything vests at `at`
+    Cliff {
+        at: u64,
+    },
+    // nothing vests before `cliff`, then linearly from `start` until `end`
+    Linear {
+        start: u64,
+        cliff: u64,
+        end: u64,
+    },
+    // an equal share vests every `interval` after `start`
+    Step {
+        start: u64,
+        interval: u64,
+        steps: u64,
+    },
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct VestingSchedule {
+    pub id: u64,
+    pub total: Nat,
+    pub released: Nat,
+    pub vesting: Vesting,
+}
+
+impl Vesting {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Vesting::Linear { start, cliff, end }
+                if !(start <= cliff && cliff <= end && start < end) =>
+            {
+                Err("linear vesting needs start <= cliff <= end and start < end".to_string())
+            }
+            Vesting::Step {
+                interval, steps, ..
+            } if *interval == 0 || *steps == 0 => {
+                Err("step vesting needs a positive interval and number of steps".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl VestingSchedule {
+    /// Amount vested at `now`, released or not.
+    pub fn vested(&self, now: u64) -> Nat {
+        match self.vesting {
+            Vesting::Cliff { at } if now >= at => self.total.clone(),
+            Vesting::Cliff { .. } => Nat::from(0),
+            Vesting::Linear { cliff, .. } if now < cliff => Nat::from(0),
+            Vesting::Linear { end, .. } if now >= end => self.total.clone(),
+            Vesting::Linear { start, end, .. } => {
+                self.total.clone() * (now - start) / (end - start)
+            }
+            Vesting::Step { start, .. } if now < start => Nat::from(0),
+            Vesting::Step {
+                start,
+                interval,
+                steps,
+            } => self.total.clone() * std::cmp::min((now - start) / interval, steps) / steps,
+        }
+    }
+
+    pub fn locked(&self) -> Nat {
+        self.total.clone() - self.released.clone()
+    }
+}
+
+pub fn locked(user: &Principal) -> Nat {
+    storage::vesting_schedules(user)
+        .iter()
+        .fold(Nat::from(0), |locked, s| locked + s.locked())
+}